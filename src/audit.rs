@@ -0,0 +1,115 @@
+use crate::imports::*;
+use crate::symbols::*;
+
+/// Events kept before the oldest are dropped.
+const MAX_SECURITY_EVENTS: usize = 5000;
+/// Shown by `/security` without a count.
+pub const SECURITY_EVENTS_SHOWN: usize = 20;
+
+/// Something worth looking at when investigating an incident later.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum SecurityEvent {
+    LoginSucceeded,
+    /// Wrong password, or the server refused the login.
+    LoginFailed(String),
+    /// The server has a key on record for our own account that isn't ours.
+    OwnKeyMismatch,
+    /// The server claims we are a different user than before.
+    OwnUidMismatch { stored: UserId, claimed: UserId },
+    OwnKeyRotated { old: String, new: String },
+    DeviceLinked { name: String, fingerprint: String },
+    IdentityRestored { fingerprint: String },
+    KeyPinned { uid: UserId, fingerprint: String },
+    /// `vouched` if the previous key signed off on the new one, which is then accepted right away.
+    KeyChanged { uid: UserId, pinned: String, offered: String, vouched: bool },
+    KeyAccepted { uid: UserId, fingerprint: String },
+    Verified { uid: UserId },
+    DecryptFailed { from: UserId, to: MessageActor, reason: String },
+    /// Dropped by `ReplayGuard`, or delivered to us while addressed to someone else.
+    ReplayDetected { from: UserId, to: MessageActor, reason: String },
+}
+
+impl Display for SecurityEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecurityEvent::LoginSucceeded => write!(f, "logged in"),
+            SecurityEvent::LoginFailed(why) => write!(f, "login failed: {}", why),
+            SecurityEvent::OwnKeyMismatch => write!(f, "server has a different key on record for this account"),
+            SecurityEvent::OwnUidMismatch { stored, claimed } => {
+                write!(f, "server claimed this account is user {}, but it was {}", claimed, stored)
+            }
+            SecurityEvent::OwnKeyRotated { old, new } => write!(f, "own key replaced: {} -> {}", old, new),
+            SecurityEvent::DeviceLinked { name, fingerprint } => write!(f, "linked device {:?} ({})", name, fingerprint),
+            SecurityEvent::IdentityRestored { fingerprint } => write!(f, "identity {} restored from backup", fingerprint),
+            SecurityEvent::KeyPinned { uid, fingerprint } => write!(f, "pinned key of {} ({})", uid, fingerprint),
+            SecurityEvent::KeyChanged { uid, pinned, offered, vouched: true } => {
+                write!(f, "key of {} changed {} -> {}, signed off by the previous key", uid, pinned, offered)
+            }
+            SecurityEvent::KeyChanged { uid, pinned, offered, vouched: false } => {
+                write!(f, "KEY OF {} CHANGED {} -> {}, blocked until accepted", uid, pinned, offered)
+            }
+            SecurityEvent::KeyAccepted { uid, fingerprint } => write!(f, "accepted key of {} ({})", uid, fingerprint),
+            SecurityEvent::Verified { uid } => write!(f, "verified {}", uid),
+            SecurityEvent::DecryptFailed { from, to, reason } => {
+                write!(f, "cannot decrypt message from {} to {}: {}", from, to, reason)
+            }
+            SecurityEvent::ReplayDetected { from, to, reason } => {
+                write!(f, "dropped message from {} to {}: {}", from, to, reason)
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SecurityRecord {
+    pub time: DateTime<Utc>,
+    pub event: SecurityEvent,
+}
+
+/// Security relevant events of one account, kept after the terminal output is gone.
+/// Only ever appended to, the oldest events are dropped past `MAX_SECURITY_EVENTS`.
+#[derive(Serialize, Deserialize, Default)]
+pub struct SecurityLog {
+    #[serde(skip)]
+    path: PathBuf,
+    records: Vec<SecurityRecord>,
+}
+
+impl SecurityLog {
+    /// Kept next to the config, e.g. `alice.json` -> `alice.security_log.json`.
+    pub fn path_for(cfg_path: &Path) -> PathBuf {
+        cfg_path.with_extension("security_log.json")
+    }
+
+    /// A missing file just means nothing happened yet.
+    pub fn load(path: PathBuf) -> Result<Self, ConfigError> {
+        let mut log = if path.exists() {
+            load_private_json::<SecurityLog>(&path)?
+        } else {
+            SecurityLog::default()
+        };
+        log.path = path;
+        Ok(log)
+    }
+
+    pub fn save(&self) -> Result<(), ConfigError> {
+        save_private_json(&self.path, self)
+    }
+
+    /// Appends `event` and saves right away, an event lost to a crash is the one that matters.
+    pub fn record(&mut self, event: SecurityEvent) {
+        self.records.push(SecurityRecord { time: Utc::now(), event });
+        if self.records.len() > MAX_SECURITY_EVENTS {
+            let excess = self.records.len() - MAX_SECURITY_EVENTS;
+            self.records.drain(..excess);
+        }
+        if let Err(e) = self.save() {
+            error!("Failed to save security log: {}", e);
+        }
+    }
+
+    /// The last `count` events, oldest first.
+    pub fn recent(&self, count: usize) -> &[SecurityRecord] {
+        &self.records[self.records.len().saturating_sub(count)..]
+    }
+}
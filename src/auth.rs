@@ -1,62 +1,633 @@
-use std::convert::TryFrom;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    string::FromUtf8Error,
+};
 
 use crate::imports::*;
 use crate::symbols::*;
-use openssl::{error::ErrorStack, pkey::{Public, Private}, rsa::{Padding, Rsa}};
+use openssl::{error::ErrorStack, rand::rand_bytes};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
 
 #[derive(Serialize, Deserialize)]
 pub struct LocalServerEntry {
     pub http_addr: String,
     pub ws_addr: String,
     pub email: String,
-    pub phash: String,
-    pub identity: LocalIdentity
+    /// Own user id, looked up after logging in. Sealed into every message sent, see `ReplayGuard`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<UserId>,
+    /// Only found in configs written before the private key was encrypted.
+    /// It is derived from the password at login now, and dropped once such a config is migrated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phash: Option<SecretString>,
+    #[serde(default)]
+    pub kdf: PasswordKdf,
+    /// Set while the server may already expect hashes from this KDF instead of `kdf`, i.e. an
+    /// upgrade was sent but not answered. Login falls back to it if `kdf` is refused.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_kdf: Option<PasswordKdf>,
+    /// How much message lengths are hidden, traded against bandwidth.
+    #[serde(default)]
+    pub padding: PaddingPolicy,
+    /// Name of this device if it was linked to an account whose main key lives on another one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    pub identity: LocalIdentity,
+    /// Identities replaced by `rotate-key`, kept to read older messages.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keyring: Vec<LocalIdentity>,
+    /// Set while the server may not have the key `rotate-key` switched to yet.
+    /// The next `rotate-key` uploads it again instead of making another one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_key_change: Option<KeyChangeNotice>
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(serde_json::Error),
+    /// Other users may read the file. Refused since it holds the private key.
+    WorldReadable(PathBuf),
+    /// Parses, but a value is out of range.
+    Invalid(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "cannot access config ({})", e),
+            ConfigError::Parse(e) => write!(f, "malformed config ({})", e),
+            ConfigError::WorldReadable(p) => write!(f, "{:?} is readable by other users, run `chmod 600 {:?}` first", p, p),
+            ConfigError::Invalid(e) => write!(f, "invalid config ({})", e),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(e: std::io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ConfigError {
+    fn from(e: serde_json::Error) -> Self {
+        ConfigError::Parse(e)
+    }
+}
+
+impl LocalServerEntry {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let cfg: Self = load_private_json(path)?;
+        cfg.padding.check().map_err(ConfigError::Invalid)?;
+        Ok(cfg)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ConfigError> {
+        save_private_json(path, self)
+    }
+}
+
+/// Reads a JSON file holding secrets or trust decisions, refusing it if other users can read it.
+pub fn load_private_json<T: DeserializeOwned>(path: &Path) -> Result<T, ConfigError> {
+    let f = File::open(path)?;
+    if f.metadata()?.permissions().mode() & 0o004 != 0 {
+        return Err(ConfigError::WorldReadable(path.to_owned()));
+    }
+    Ok(serde_json::from_reader(BufReader::new(f))?)
+}
+
+/// Writes to a temporary file first so a crash can't leave a half-written file behind.
+/// New files are only accessible by the owner.
+pub fn save_private_json<T: Serialize>(path: &Path, value: &T) -> Result<(), ConfigError> {
+    let tmp = path.with_extension("tmp");
+    let f = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    let mut buf = BufWriter::new(f);
+    serde_json::to_writer_pretty(&mut buf, value)?;
+    buf.flush()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct LocalIdentity {
+    /// PEM. Encrypted PKCS#8 unless the config predates passphrase protection.
+    /// The signing key for curve identities.
     pub privkey: String,
+    /// PEM of the X25519 key agreement key of curve identities, encrypted like `privkey`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dh_privkey: Option<String>,
+    /// As published, the algorithm can be told from it, see `CURVE25519_PREFIX`.
     pub pubkey: String
 }
 
-pub struct InMemoryKey {
-    rsa_priv: Rsa<Private>,
-    rsa_pub: Rsa<Public>
+impl LocalIdentity {
+    /// Generates a new identity. The private key is encrypted with `passphrase`.
+    pub fn generate(algorithm: KeyAlgorithm, passphrase: &str) -> Result<Self, CryptoError> {
+        let private = PrivateIdentity::generate(algorithm)?;
+        let (privkey, dh_privkey) = private.to_pem(passphrase)?;
+        Ok(Self {
+            privkey,
+            dh_privkey,
+            pubkey: private.public()?.encode()?.to_string()
+        })
+    }
+
+    pub fn algorithm(&self) -> KeyAlgorithm {
+        KeyAlgorithm::of(&Pubkey::from(self.pubkey.clone()))
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.privkey.contains("BEGIN ENCRYPTED PRIVATE KEY")
+    }
+
+    /// Decrypts the private key with `passphrase`. Plaintext keys from old configs are loaded as-is.
+    pub fn unlock(&self, passphrase: &str) -> Result<PrivateIdentity, CryptoError> {
+        let passphrase = if self.is_encrypted() { Some(passphrase) } else { None };
+        PrivateIdentity::from_pem(self.algorithm(), &self.privkey, self.dh_privkey.as_deref(), passphrase)
+    }
+
+    /// Encrypts a plaintext private key left over from an old config.
+    pub fn protect(&mut self, passphrase: &str) -> Result<(), CryptoError> {
+        if !self.is_encrypted() {
+            let (privkey, dh_privkey) = self.unlock(passphrase)?.to_pem(passphrase)?;
+            self.privkey = privkey;
+            self.dh_privkey = dh_privkey;
+        }
+        Ok(())
+    }
 }
 
-impl InMemoryKey {
-    /// Input message was signed using **private key of self.**
-    pub fn encrypt(&self, msg: &str) -> Option<ClientMessage> {
-        let mut res = vec![0; 256];
-        let bytes_written = self.rsa_priv.private_encrypt(msg.to_string().as_bytes(), &mut res, Padding::PKCS1).ok()?;
-        let content = hex::encode(res);
-        Some(ClientMessage::from(content))
+/// How the password is turned into the credential sent to the server.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum PasswordKdf {
+    /// Unsalted SHA-256. Only kept to log in with configs written before salting,
+    /// which get upgraded on login.
+    LegacySha256,
+    Argon2id {
+        /// Hex, per account.
+        salt: String,
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    },
+}
+
+/// Configs without a `kdf` field predate salting.
+impl Default for PasswordKdf {
+    fn default() -> Self {
+        Self::LegacySha256
     }
 }
 
-impl TryFrom<LocalIdentity> for InMemoryKey {
-    type Error = ErrorStack;
+const SALT_LEN: usize = 16;
 
-    fn try_from(value: LocalIdentity) -> Result<Self, Self::Error> {
-        let rsa_priv = Rsa::private_key_from_pem(value.privkey.as_bytes())?;
-        let rsa_pub = Rsa::public_key_from_pem(value.pubkey.as_bytes())?;
-        Ok(Self {
-            rsa_priv,
-            rsa_pub
+impl PasswordKdf {
+    /// Argon2id with a fresh random salt and the recommended cost parameters.
+    pub fn generate() -> Result<Self, CryptoError> {
+        let mut salt = [0; SALT_LEN];
+        rand_bytes(&mut salt).map_err(CryptoError::Internal)?;
+        Ok(Self::Argon2id {
+            salt: hex::encode(salt),
+            m_cost: argon2::Params::DEFAULT_M_COST,
+            t_cost: argon2::Params::DEFAULT_T_COST,
+            p_cost: argon2::Params::DEFAULT_P_COST,
         })
     }
+
+    pub fn is_legacy(&self) -> bool {
+        matches!(self, Self::LegacySha256)
+    }
+
+    /// Hex encoded password hash, used as `password_hash` in `RegisterRequest` and `LoginRequest`.
+    pub fn derive(&self, password: &str) -> Result<SecretString, CryptoError> {
+        match self {
+            Self::LegacySha256 => {
+                let mut hasher = Sha256::new();
+                hasher.update(password.as_bytes());
+                let out = SecretBytes::new(hasher.finalize().to_vec());
+                Ok(SecretString::from(hex::encode(&*out)))
+            }
+            Self::Argon2id { salt, m_cost, t_cost, p_cost } => {
+                let salt = unhex("salt", salt)?;
+                let params = argon2::Params::new(*m_cost, *t_cost, *p_cost, None)
+                    .map_err(|e| CryptoError::Kdf(e.to_string()))?;
+                let mut out = SecretBytes::new(vec![0; argon2::Params::DEFAULT_OUTPUT_LEN]);
+                argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                    .hash_password_into(password.as_bytes(), &salt, &mut out)
+                    .map_err(|e| CryptoError::Kdf(e.to_string()))?;
+                Ok(SecretString::from(hex::encode(&*out)))
+            }
+        }
+    }
+}
+
+pub struct InMemoryKey {
+    private: PrivateIdentity,
+    public: PublicIdentity,
+    fingerprint: String,
+    /// Rotated out keys and their fingerprints, only used to decrypt older messages.
+    retired: Vec<(String, PrivateIdentity)>,
+    padding: PaddingPolicy
+}
+
+/// Current `Envelope` format. Bump whenever the layout or the meaning of a field changes.
+///
+/// 1. RSA only.
+/// 2. Adds `WrappedKey::ephemeral` for curve identities.
+/// 3. and 4. were ratchet and group messages before padding, no longer read.
+/// 5. Plaintext is padded, see `PaddingPolicy`.
+///
+/// 6. and 7. are taken by `RATCHET_VERSION` and `GROUP_VERSION`, which travel in `ClientMessage` too.
+pub const ENVELOPE_VERSION: u8 = 5;
+/// Oldest `Envelope` format that can still be read.
+pub const MIN_ENVELOPE_VERSION: u8 = 1;
+
+/// What actually travels inside a `ClientMessage`, serialized as JSON. Binary fields are hex encoded.
+///
+/// The text is encrypted once with a random AES-256-GCM content key, which is then wrapped
+/// for every recipient (see `PublicIdentity::wrap`), so there is no limit on the message length.
+/// `signature` is made with the sender's private key and covers everything else.
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    version: u8,
+    keys: Vec<WrappedKey>,
+    nonce: String,
+    /// Ciphertext followed by the GCM tag.
+    ciphertext: String,
+    signature: String
+}
+
+impl Envelope {
+    /// Bytes covered by the signature. Every field is length-prefixed so they can't be shifted around.
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut out = vec![self.version];
+        let mut push = |field: &str| {
+            out.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            out.extend_from_slice(field.as_bytes());
+        };
+        for k in &self.keys {
+            push(&k.fingerprint);
+            push(&k.key);
+            if self.version >= 2 {
+                push(k.ephemeral.as_deref().unwrap_or(""));
+            }
+        }
+        push(&self.nonce);
+        push(&self.ciphertext);
+        out
+    }
+}
+
+/// Upper bound on the plaintext of a single message.
+pub const MAX_MESSAGE_LEN: usize = 64 * 1024;
+/// Upper bound after `PaddingPolicy::pad`, the end marker may push a full message over `MAX_MESSAGE_LEN`.
+pub const MAX_PADDED_LEN: usize = MAX_MESSAGE_LEN + 1;
+
+/// How plaintexts are padded before encryption, so their length only tells which bucket they fall in.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum PaddingPolicy {
+    /// Only the end marker, the length shows through.
+    Off,
+    /// Next power of two, at least `min` bytes. Hides the most, at up to twice the size.
+    PowerOfTwo { min: usize },
+    /// Next multiple of `block` bytes.
+    Block { block: usize },
 }
 
-impl PublicUserRecord {
-    /// Input message was signed with **private key of origin.**
-    pub fn decrypt(&self, msg: ClientMessage) -> Option<String> {
-        let in_bytes = msg.to_string();
-        let pubkey = Rsa::public_key_from_pem(self.pubkey.to_string().as_bytes()).ok()?;
-        let mut res = vec![0; 10000];
-        let unhexed = hex::decode(in_bytes.as_bytes()).ok()?;
+/// Short messages all look the same, longer ones cost at most twice their size.
+impl Default for PaddingPolicy {
+    fn default() -> Self {
+        Self::PowerOfTwo { min: 256 }
+    }
+}
+
+impl PaddingPolicy {
+    /// Buckets larger than a message can be would pad every message to `MAX_PADDED_LEN`.
+    pub fn check(&self) -> Result<(), String> {
+        match *self {
+            Self::PowerOfTwo { min } if min > MAX_PADDED_LEN => {
+                Err(format!("padding min {} is above {}", min, MAX_PADDED_LEN))
+            }
+            Self::Block { block } if block > MAX_PADDED_LEN => {
+                Err(format!("padding block {} is above {}", block, MAX_PADDED_LEN))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// `data` followed by 0x80 and zeros up to the bucket size (ISO/IEC 7816-4).
+    pub fn pad(&self, data: &[u8]) -> Vec<u8> {
+        let unpadded = data.len() + 1;
+        let bucket = match *self {
+            Self::Off => Some(unpadded),
+            Self::PowerOfTwo { min } => unpadded.max(min).checked_next_power_of_two(),
+            Self::Block { block } if block > 0 => unpadded.div_ceil(block).checked_mul(block),
+            Self::Block { .. } => Some(unpadded),
+        };
+        // policies that weren't checked may overflow, those get the largest bucket
+        let bucket = bucket.unwrap_or(MAX_PADDED_LEN).min(MAX_PADDED_LEN).max(unpadded);
+        let mut out = Vec::with_capacity(bucket);
+        out.extend_from_slice(data);
+        out.push(0x80);
+        out.resize(bucket, 0);
+        out
+    }
+}
+
+/// Strips what `PaddingPolicy::pad` added, whatever the sender's policy was.
+/// Malformed padding is reported like a failed tag check.
+pub fn unpad(mut padded: Vec<u8>) -> Result<Vec<u8>, CryptoError> {
+    let end = padded.iter().rposition(|b| *b != 0).ok_or(CryptoError::PaddingOrAuth)?;
+    if padded[end] != 0x80 {
+        return Err(CryptoError::PaddingOrAuth);
+    }
+    padded.truncate(end);
+    Ok(padded)
+}
+
+#[derive(Debug)]
+pub enum CryptoError {
+    /// Envelope isn't valid JSON or a field isn't valid hex.
+    Decode(String),
+    /// A key could not be parsed.
+    KeyParse(ErrorStack),
+    /// Wrong RSA padding, bad signature or the GCM tag didn't match.
+    /// These are deliberately not told apart.
+    PaddingOrAuth,
+    /// Message is longer than `MAX_MESSAGE_LEN`.
+    Oversize(usize),
+    /// Wrong passphrase for the private key, or the key is corrupted.
+    Locked,
+    /// Password hashing failed, usually because of bad parameters in the config.
+    Kdf(String),
+    /// Decrypted fine but isn't UTF-8.
+    Utf8(FromUtf8Error),
+    /// Envelope was produced by an unknown format version.
+    UnsupportedVersion(u8),
+    /// None of the wrapped keys were meant for us.
+    NotRecipient,
+    /// No forward-secret session to decrypt with, or the peer can't have one.
+    NoSession,
+    /// The message key is gone, used for a copy of the message read before.
+    AlreadyRead,
+    /// Needs something the identity or the peer doesn't have, e.g. a curve key.
+    Unsupported(&'static str),
+    /// Parses, but is too weak to be used, see `PublicIdentity::validate`.
+    WeakKey(String),
+    /// OpenSSL failed on our side, e.g. while generating randomness.
+    Internal(ErrorStack),
+}
+
+impl Display for CryptoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CryptoError::Decode(what) => write!(f, "malformed {}", what),
+            CryptoError::KeyParse(e) => write!(f, "unusable key ({})", e),
+            CryptoError::PaddingOrAuth => write!(f, "authentication failed, message was tampered with or not meant for us"),
+            CryptoError::Oversize(len) => write!(f, "message too long ({} > {} bytes)", len, MAX_MESSAGE_LEN),
+            CryptoError::Locked => write!(f, "wrong passphrase or corrupted private key"),
+            CryptoError::Kdf(e) => write!(f, "cannot derive password hash ({})", e),
+            CryptoError::Utf8(e) => write!(f, "content is not valid UTF-8 ({})", e),
+            CryptoError::UnsupportedVersion(v) => write!(f, "unsupported envelope version {}", v),
+            CryptoError::NotRecipient => write!(f, "message has no key for us"),
+            CryptoError::NoSession => write!(f, "no session for this message"),
+            CryptoError::AlreadyRead => write!(f, "message was read before"),
+            CryptoError::Unsupported(what) => write!(f, "not supported: {}", what),
+            CryptoError::WeakKey(why) => write!(f, "key rejected ({})", why),
+            CryptoError::Internal(e) => write!(f, "internal crypto failure ({})", e),
+        }
+    }
+}
+
+impl Error for CryptoError {}
+
+impl InMemoryKey {
+    /// Output message is sealed to the **public keys of the recipient's devices** and signed using **private key of self.**
+    ///
+    /// The content key is also wrapped for self, so our own messages can be read back later.
+    pub fn encrypt(&self, to: &CachedUser, msg: &str) -> Result<ClientMessage, CryptoError> {
+        if msg.len() > MAX_MESSAGE_LEN {
+            return Err(CryptoError::Oversize(msg.len()));
+        }
+        let content_key = random_bytes(AEAD_KEY_LEN)?;
+        let nonce = random_bytes(AEAD_NONCE_LEN)?;
+        let ciphertext = seal_aead(&content_key, &nonce, &[ENVELOPE_VERSION], &self.padding.pad(msg.as_bytes()))?;
+        let mut keys = to.identities().map(|i| i.wrap(&content_key)).collect::<Result<Vec<_>, _>>()?;
+        keys.push(self.public.wrap(&content_key)?);
+        let mut envelope = Envelope {
+            version: ENVELOPE_VERSION,
+            keys,
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+            signature: String::new()
+        };
+        envelope.signature = hex::encode(self.private.sign(&envelope.signed_bytes())?);
+        let serialized = serde_json::to_string(&envelope).map_err(|_| CryptoError::Decode("envelope".to_owned()))?;
+        Ok(ClientMessage::from(serialized))
+    }
+
+    /// Input message was sealed to the **public key of self** and signed with **private key of origin**,
+    /// or of one of its linked devices.
+    pub fn decrypt(&self, from: &CachedUser, msg: ClientMessage) -> Result<String, CryptoError> {
+        let envelope: Envelope = serde_json::from_str(&msg.to_string())
+            .map_err(|_| CryptoError::Decode("envelope".to_owned()))?;
+        if envelope.version < MIN_ENVELOPE_VERSION
+            || envelope.version > ENVELOPE_VERSION
+            || (3..=4).contains(&envelope.version)
+        {
+            return Err(CryptoError::UnsupportedVersion(envelope.version));
+        }
+        // hex doubles the size, tag and nonce are tiny in comparison
+        if envelope.ciphertext.len() / 2 > MAX_PADDED_LEN + AEAD_TAG_LEN {
+            return Err(CryptoError::Oversize(envelope.ciphertext.len() / 2 - AEAD_TAG_LEN));
+        }
+        let signature = unhex("signature", &envelope.signature)?;
+        let signed = envelope.signed_bytes();
+        if !from.identities().any(|i| i.verify(&signed, &signature)) {
+            return Err(CryptoError::PaddingOrAuth);
+        }
+        debug!("signature ok");
+        let (wrapped, private) = envelope.keys.iter()
+            .find_map(|k| if k.fingerprint == self.fingerprint {
+                Some((k, &self.private))
+            } else {
+                self.retired.iter().find(|(fp, _)| fp == &k.fingerprint).map(|(_, private)| (k, private))
+            })
+            .ok_or(CryptoError::NotRecipient)?;
+        let content_key = private.unwrap(wrapped)?;
+        let nonce = unhex("nonce", &envelope.nonce)?;
+        let ciphertext = unhex("ciphertext", &envelope.ciphertext)?;
         debug!("unhex ok");
-        let bytes_written = pubkey.public_decrypt(&unhexed, &mut res, Padding::PKCS1).ok()?;
+        let mut res = open_aead(&content_key, &nonce, &[envelope.version], &ciphertext)?;
+        if envelope.version >= 5 {
+            res = unpad(res)?;
+        }
         debug!("content decode ok");
-        String::from_utf8(res).ok()
+        String::from_utf8(res).map_err(CryptoError::Utf8)
+    }
+
+    /// Decrypts the private key of `identity`, and of every retired identity in `keyring`, with `passphrase`.
+    /// Plaintext keys from old configs are loaded as-is.
+    pub fn unlock(identity: &LocalIdentity, keyring: &[LocalIdentity], passphrase: &str) -> Result<Self, CryptoError> {
+        let private = identity.unlock(passphrase)?;
+        let public = PublicIdentity::parse(&Pubkey::from(identity.pubkey.clone()))?;
+        let retired = keyring
+            .iter()
+            .map(|old| {
+                let fp = pubkey_fingerprint(&Pubkey::from(old.pubkey.clone()))?;
+                Ok((fp, old.unlock(passphrase)?))
+            })
+            .collect::<Result<_, CryptoError>>()?;
+        Ok(Self {
+            private,
+            fingerprint: public.fingerprint()?,
+            public,
+            retired,
+            padding: PaddingPolicy::default()
+        })
+    }
+
+    /// Policy for messages encrypted from now on, usually `LocalServerEntry::padding`.
+    pub fn set_padding(&mut self, padding: PaddingPolicy) {
+        self.padding = padding;
+    }
+
+    pub fn padding(&self) -> PaddingPolicy {
+        self.padding
     }
-}
\ No newline at end of file
+
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    pub fn private(&self) -> &PrivateIdentity {
+        &self.private
+    }
+
+    pub fn public(&self) -> &PublicIdentity {
+        &self.public
+    }
+
+    /// Announces `new` as our next identity, vouched for by the current key.
+    pub fn sign_key_change(&self, new: &LocalIdentity) -> Result<KeyChangeNotice, CryptoError> {
+        let mut notice = KeyChangeNotice {
+            old_pubkey: self.public.encode()?,
+            new_pubkey: Pubkey::from(new.pubkey.clone()),
+            time: Utc::now(),
+            signature: String::new()
+        };
+        notice.signature = hex::encode(self.private.sign(&key_change_bytes(&notice))?);
+        Ok(notice)
+    }
+}
+
+/// Hex SHA-256 of the DER encoded public key, stable across PEM formatting differences.
+pub fn pubkey_fingerprint(pubkey: &Pubkey) -> Result<String, CryptoError> {
+    PublicIdentity::parse(pubkey)?.fingerprint()
+}
+
+fn key_change_bytes(notice: &KeyChangeNotice) -> Vec<u8> {
+    format!(
+        "yap key change\n{}\n{}\n{}",
+        notice.old_pubkey.to_string(),
+        notice.new_pubkey.to_string(),
+        notice.time.to_rfc3339()
+    ).into_bytes()
+}
+
+impl KeyChangeNotice {
+    /// Checks that `old_pubkey` really signed off on `new_pubkey`.
+    /// Whether `old_pubkey` is a key we trust is up to the caller.
+    pub fn verify(&self) -> Result<(), CryptoError> {
+        let signature = unhex("signature", &self.signature)?;
+        let old = PublicIdentity::parse(&self.old_pubkey)?;
+        if old.verify(&key_change_bytes(self), &signature) {
+            Ok(())
+        } else {
+            Err(CryptoError::PaddingOrAuth)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICIES: [PaddingPolicy; 5] = [
+        PaddingPolicy::Off,
+        PaddingPolicy::PowerOfTwo { min: 256 },
+        PaddingPolicy::PowerOfTwo { min: 0 },
+        PaddingPolicy::Block { block: 100 },
+        PaddingPolicy::Block { block: 0 },
+    ];
+
+    #[test]
+    fn pad_round_trips() {
+        for policy in POLICIES.iter() {
+            for len in [0, 1, 255, 256, 1000, MAX_MESSAGE_LEN].iter() {
+                // trailing zeros and 0x80 inside the data must survive
+                let data: Vec<u8> = (0..*len).map(|i| if i % 3 == 0 { 0x80 } else { 0 }).collect();
+                let padded = policy.pad(&data);
+                assert!(padded.len() > data.len());
+                assert!(padded.len() <= MAX_PADDED_LEN);
+                assert_eq!(unpad(padded).unwrap(), data, "{:?}, {} bytes", policy, len);
+            }
+        }
+    }
+
+    #[test]
+    fn pad_bucket_sizes() {
+        let default = PaddingPolicy::default();
+        assert_eq!(default.pad(b"").len(), 256);
+        assert_eq!(default.pad(&[1; 255]).len(), 256);
+        assert_eq!(default.pad(&[1; 256]).len(), 512);
+        assert_eq!(default.pad(&[1; 1000]).len(), 1024);
+        let block = PaddingPolicy::Block { block: 100 };
+        assert_eq!(block.pad(b"").len(), 100);
+        assert_eq!(block.pad(&[1; 99]).len(), 100);
+        assert_eq!(block.pad(&[1; 100]).len(), 200);
+        assert_eq!(PaddingPolicy::Off.pad(b"hi").len(), 3);
+        assert_eq!(PaddingPolicy::Block { block: 0 }.pad(b"hi").len(), 3);
+        // never beyond what a message can take, even if the bucket is larger
+        assert_eq!(default.pad(&vec![1; MAX_MESSAGE_LEN]).len(), MAX_PADDED_LEN);
+    }
+
+    #[test]
+    fn huge_policies_do_not_panic() {
+        for policy in [
+            PaddingPolicy::PowerOfTwo { min: usize::MAX },
+            PaddingPolicy::PowerOfTwo { min: usize::MAX / 2 + 2 },
+            PaddingPolicy::Block { block: usize::MAX },
+            PaddingPolicy::Block { block: usize::MAX / 2 + 2 },
+        ]
+        .iter()
+        {
+            assert!(policy.check().is_err());
+            assert_eq!(policy.pad(b"hi").len(), MAX_PADDED_LEN);
+            assert_eq!(unpad(policy.pad(b"hi")).unwrap(), b"hi");
+        }
+        for policy in POLICIES.iter() {
+            assert!(policy.check().is_ok());
+        }
+        assert!(PaddingPolicy::Block { block: MAX_PADDED_LEN }.check().is_ok());
+    }
+
+    #[test]
+    fn unpad_rejects_malformed_padding() {
+        assert!(matches!(unpad(Vec::new()), Err(CryptoError::PaddingOrAuth)));
+        assert!(matches!(unpad(vec![0, 0]), Err(CryptoError::PaddingOrAuth)));
+        assert!(matches!(unpad(vec![1, 2]), Err(CryptoError::PaddingOrAuth)));
+        assert!(matches!(unpad(vec![0x80, 1, 0]), Err(CryptoError::PaddingOrAuth)));
+        assert_eq!(unpad(vec![1, 0x80, 0, 0]).unwrap(), vec![1]);
+    }
+}
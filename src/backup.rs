@@ -0,0 +1,154 @@
+use crate::imports::*;
+use crate::symbols::*;
+
+use std::{fs::OpenOptions, io::Write, os::unix::fs::OpenOptionsExt};
+
+/// Current `BackupFile` format.
+pub const BACKUP_VERSION: u8 = 1;
+const BACKUP_BEGIN: &str = "-----BEGIN YAP IDENTITY BACKUP-----";
+const BACKUP_END: &str = "-----END YAP IDENTITY BACKUP-----";
+/// Hex characters per line of recovery text, short enough to print or copy by hand.
+const BACKUP_LINE_LEN: usize = 64;
+
+/// Everything needed to log in with an account again after losing its config.
+/// Private keys stay encrypted with the account password, as they are in the config.
+#[derive(Serialize, Deserialize)]
+pub struct IdentityBackup {
+    pub http_addr: String,
+    pub ws_addr: String,
+    pub email: String,
+    /// The server only knows the hash derived with it, logging in needs the same salt.
+    pub kdf: PasswordKdf,
+    pub padding: PaddingPolicy,
+    #[serde(default)]
+    pub device: Option<String>,
+    pub identity: LocalIdentity,
+    #[serde(default)]
+    pub keyring: Vec<LocalIdentity>,
+    pub created: DateTime<Utc>,
+}
+
+/// `IdentityBackup` sealed with a key derived from the backup passphrase, which may differ
+/// from the account password. Hex encoded between `BACKUP_BEGIN` and `BACKUP_END`.
+#[derive(Serialize, Deserialize)]
+struct BackupFile {
+    version: u8,
+    kdf: PasswordKdf,
+    sealed: SealedState,
+}
+
+#[derive(Debug)]
+pub enum RestoreError {
+    /// The server publishes one of the retired keys, the backup was made before a `rotate-key`.
+    Outdated,
+    /// The server publishes a key that isn't in the backup at all.
+    Mismatch,
+    Crypto(CryptoError),
+}
+
+impl Display for RestoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RestoreError::Outdated => write!(f, "the server has a newer key for this account than the backup"),
+            RestoreError::Mismatch => write!(f, "the server has a different key for this account than the backup"),
+            RestoreError::Crypto(e) => write!(f, "cannot check published key ({})", e),
+        }
+    }
+}
+
+impl Error for RestoreError {}
+
+impl IdentityBackup {
+    pub fn of(cfg: &LocalServerEntry) -> Self {
+        Self {
+            http_addr: cfg.http_addr.clone(),
+            ws_addr: cfg.ws_addr.clone(),
+            email: cfg.email.clone(),
+            kdf: cfg.kdf.clone(),
+            padding: cfg.padding,
+            device: cfg.device.clone(),
+            identity: cfg.identity.clone(),
+            keyring: cfg.keyring.clone(),
+            created: Utc::now(),
+        }
+    }
+
+    /// Config to save once restored. The user id is looked up again on login.
+    pub fn to_config(&self) -> LocalServerEntry {
+        LocalServerEntry {
+            http_addr: self.http_addr.clone(),
+            ws_addr: self.ws_addr.clone(),
+            email: self.email.clone(),
+            uid: None,
+            phash: None,
+            kdf: self.kdf.clone(),
+            pending_kdf: None,
+            padding: self.padding,
+            device: self.device.clone(),
+            identity: self.identity.clone(),
+            keyring: self.keyring.clone(),
+            pending_key_change: None,
+        }
+    }
+
+    /// Recovery text that can be printed or kept as a file, read back with `from_text`.
+    pub fn to_text(&self, passphrase: &str) -> Result<String, CryptoError> {
+        let kdf = PasswordKdf::generate()?;
+        let sealed = SealedState::seal(&backup_key(&kdf, passphrase)?, "identity backup", self)?;
+        let file = BackupFile {
+            version: BACKUP_VERSION,
+            kdf,
+            sealed,
+        };
+        let encoded = hex::encode(serde_json::to_vec(&file).map_err(|_| CryptoError::Decode("identity backup".to_owned()))?);
+        let mut out = format!("{}\n", BACKUP_BEGIN);
+        for line in encoded.as_bytes().chunks(BACKUP_LINE_LEN) {
+            out.push_str(std::str::from_utf8(line).unwrap());
+            out.push('\n');
+        }
+        out.push_str(BACKUP_END);
+        out.push('\n');
+        Ok(out)
+    }
+
+    /// Reads what `to_text` wrote. Whitespace and anything outside the markers is ignored,
+    /// so text copied from an email or a scan still works.
+    pub fn from_text(text: &str, passphrase: &str) -> Result<Self, CryptoError> {
+        let malformed = || CryptoError::Decode("identity backup".to_owned());
+        let start = text.find(BACKUP_BEGIN).ok_or_else(malformed)? + BACKUP_BEGIN.len();
+        let end = start + text[start..].find(BACKUP_END).ok_or_else(malformed)?;
+        let encoded: String = text[start..end].chars().filter(|c| !c.is_whitespace()).collect();
+        let file: BackupFile = serde_json::from_slice(&unhex("identity backup", &encoded)?).map_err(|_| malformed())?;
+        if file.version != BACKUP_VERSION {
+            return Err(CryptoError::UnsupportedVersion(file.version));
+        }
+        file.sealed.open(&backup_key(&file.kdf, passphrase)?, "identity backup")
+    }
+
+    /// Checks the keys the server publishes for the account, main key and linked devices,
+    /// against the backed up ones.
+    pub fn check_published(&self, published: &CachedUser) -> Result<(), RestoreError> {
+        let matches = |ident: &LocalIdentity| match pubkey_fingerprint(&Pubkey::from(ident.pubkey.clone())) {
+            Ok(fp) => published.has_key(&fp),
+            Err(_) => false,
+        };
+        if matches(&self.identity) {
+            Ok(())
+        } else if self.keyring.iter().any(matches) {
+            Err(RestoreError::Outdated)
+        } else {
+            Err(RestoreError::Mismatch)
+        }
+    }
+}
+
+/// Writes recovery text to a new file only the owner can read, never replacing an existing one.
+pub fn save_backup_text(path: &Path, text: &str) -> std::io::Result<()> {
+    let mut f = OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+    f.write_all(text.as_bytes())?;
+    f.flush()
+}
+
+fn backup_key(kdf: &PasswordKdf, passphrase: &str) -> Result<SecretBytes, CryptoError> {
+    Ok(SecretBytes::new(unhex("backup key", kdf.derive(passphrase)?.expose())?))
+}
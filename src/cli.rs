@@ -3,6 +3,11 @@ use std::{any::TypeId, str::SplitAsciiWhitespace, unimplemented};
 use crate::imports::*;
 use crate::symbols::*;
 
+use chrono::{Local, NaiveDate, TimeZone};
+
+/// How far back `/history` without arguments goes.
+const HISTORY_DEFAULT_HOURS: i64 = 24;
+
 pub fn parse(cmd: &str, state: ClientState) -> Result<CliCommand, CliParseError> {
     if cmd.is_ascii() {
         let mut tks = cmd.split_ascii_whitespace();
@@ -18,6 +23,12 @@ pub fn parse(cmd: &str, state: ClientState) -> Result<CliCommand, CliParseError>
                     "/s" => parse_s(rem_toks),
                     "/q" => parse_q(rem_toks),
                     "/j" => parse_j(rem_toks),
+                    "/accept" => parse_accept(rem_toks),
+                    "/verify" => parse_verify(rem_toks),
+                    "/security" => parse_security(rem_toks),
+                    "/history" => parse_history(rem_toks),
+                    "/groups" => Ok(CliCommand::ListGroups),
+                    "/group" => parse_group(rem_toks),
                     _ => Err(CliParseError::UnrecognizedCommand(first.to_owned()))
                 }
             } else {
@@ -60,14 +71,164 @@ pub fn parse_u(rem_toks: &mut SplitAsciiWhitespace) -> Result<CliCommand, CliPar
     }
 }
 
+pub fn parse_accept(rem_toks: &mut SplitAsciiWhitespace) -> Result<CliCommand, CliParseError> {
+    Ok(CliCommand::AcceptKey(next_uid(rem_toks)?))
+}
+
+pub fn parse_verify(rem_toks: &mut SplitAsciiWhitespace) -> Result<CliCommand, CliParseError> {
+    let uid = next_uid(rem_toks)?;
+    match rem_toks.next() {
+        None => Ok(CliCommand::Verify { uid, confirm: false }),
+        Some("confirm") => Ok(CliCommand::Verify { uid, confirm: true }),
+        Some(other) => Err(CliParseError::UnrecognizedCommand(other.to_owned()))
+    }
+}
+
+pub fn parse_security(rem_toks: &mut SplitAsciiWhitespace) -> Result<CliCommand, CliParseError> {
+    match rem_toks.next() {
+        None => Ok(CliCommand::ShowSecurityLog(SECURITY_EVENTS_SHOWN)),
+        Some(tk) => tk
+            .parse::<usize>()
+            .map(CliCommand::ShowSecurityLog)
+            .map_err(|_| CliParseError::TypeError(TypeId::of::<usize>()))
+    }
+}
+
+pub fn parse_history(rem_toks: &mut SplitAsciiWhitespace) -> Result<CliCommand, CliParseError> {
+    let now = Local::now();
+    match rem_toks.next() {
+        None => Ok(CliCommand::History(HistoryQuery::Since(
+            (now - chrono::Duration::hours(HISTORY_DEFAULT_HOURS)).with_timezone(&Utc)
+        ))),
+        Some("since") => Ok(CliCommand::History(HistoryQuery::Since(next_when(rem_toks, now, "date")?))),
+        Some("from") => {
+            let from = next_when(rem_toks, now, "date")?;
+            match rem_toks.next() {
+                Some("to") => {}
+                Some(other) => return Err(CliParseError::UnrecognizedCommand(other.to_owned())),
+                None => return Err(CliParseError::MissingExpected("to")),
+            }
+            let to = next_when(rem_toks, now, "date")?;
+            if from > to {
+                return Err(CliParseError::InvalidDate(format!("{} is after {}", from, to)));
+            }
+            Ok(CliCommand::History(HistoryQuery::Interval { from, to }))
+        }
+        Some(other) => Err(CliParseError::UnrecognizedCommand(other.to_owned()))
+    }
+}
+
+pub fn parse_group(rem_toks: &mut SplitAsciiWhitespace) -> Result<CliCommand, CliParseError> {
+    match rem_toks.next() {
+        Some("create") => Ok(CliCommand::CreateGroup { motd: rest(rem_toks) }),
+        Some(tk) => {
+            let gid = tk
+                .parse::<u32>()
+                .map(GroupId::from)
+                .map_err(|_| CliParseError::TypeError(TypeId::of::<u32>()))?;
+            match rem_toks.next() {
+                Some("invite") => Ok(CliCommand::InviteToGroup { gid, uid: next_uid(rem_toks)? }),
+                Some("leave") => Ok(CliCommand::LeaveGroup(gid)),
+                Some("members") => Ok(CliCommand::ListMembers(gid)),
+                Some("motd") => Ok(CliCommand::SetMotd { gid, motd: rest(rem_toks) }),
+                Some(other) => Err(CliParseError::UnrecognizedCommand(other.to_owned())),
+                None => Err(CliParseError::MissingExpected("invite, leave, members or motd"))
+            }
+        }
+        None => Err(CliParseError::MissingExpected("create or gid"))
+    }
+}
+
+/// The remaining tokens joined by single spaces, `None` if there are none.
+fn rest(rem_toks: &mut SplitAsciiWhitespace) -> Option<String> {
+    let words: Vec<&str> = rem_toks.collect();
+    if words.is_empty() {
+        None
+    } else {
+        Some(words.join(" "))
+    }
+}
+
+fn next_when(rem_toks: &mut SplitAsciiWhitespace, now: DateTime<Local>, what: &'static str) -> Result<DateTime<Utc>, CliParseError> {
+    match rem_toks.next() {
+        Some(tk) => parse_when(tk, now).ok_or_else(|| CliParseError::InvalidDate(tk.to_owned())),
+        None => Err(CliParseError::MissingExpected(what))
+    }
+}
+
+/// A point in time relative to `now`: `now`, `today` and `yesterday` (midnight), an age such as
+/// `30m`, `2h`, `3d` or `1w`, a date `2021-03-14`, or an RFC 3339 timestamp.
+/// Midnight and dates are in the timezone of `now`, i.e. local time for the CLI.
+pub fn parse_when<Tz: TimeZone>(tk: &str, now: DateTime<Tz>) -> Option<DateTime<Utc>> {
+    let tz = now.timezone();
+    let midnight = |date: NaiveDate| tz.from_local_datetime(&date.and_hms(0, 0, 0)).earliest();
+    let when = match tk {
+        "now" => Some(now),
+        "today" => midnight(now.date().naive_local()),
+        "yesterday" => midnight(now.date().naive_local().pred_opt()?),
+        _ => {
+            if let Ok(t) = DateTime::parse_from_rfc3339(tk) {
+                Some(t.with_timezone(&tz))
+            } else if let Ok(date) = NaiveDate::parse_from_str(tk, "%Y-%m-%d") {
+                midnight(date)
+            } else {
+                let (split, unit) = tk.char_indices().last()?;
+                let n = tk[..split].parse::<i64>().ok().filter(|n| *n >= 0)?;
+                let unit_secs = match unit {
+                    'm' => 60,
+                    'h' => 3600,
+                    'd' => 86_400,
+                    'w' => 604_800,
+                    _ => return None,
+                };
+                // chrono panics on durations it can't represent
+                let secs = n
+                    .checked_mul(unit_secs)
+                    .filter(|secs| *secs <= chrono::Duration::max_value().num_seconds())?;
+                now.checked_sub_signed(chrono::Duration::seconds(secs))
+            }
+        }
+    };
+    when.map(|t| t.with_timezone(&Utc))
+}
+
+fn next_uid(rem_toks: &mut SplitAsciiWhitespace) -> Result<UserId, CliParseError> {
+    if let Some(tk) = rem_toks.next() {
+        tk.parse::<u32>()
+            .map(UserId::from)
+            .map_err(|_| CliParseError::TypeError(TypeId::of::<u32>()))
+    } else {
+        Err(CliParseError::MissingExpected("uid"))
+    }
+}
+
 // todo
 pub fn parse_r(rem_toks: &mut SplitAsciiWhitespace) -> Result<CliCommand, CliParseError> {
     Err(CliParseError::NotImpl)
 }
 
-// todo
 pub fn parse_s(rem_toks: &mut SplitAsciiWhitespace) -> Result<CliCommand, CliParseError> {
-    Err(CliParseError::NotImpl)
+    let attr = rest(rem_toks).ok_or(CliParseError::MissingExpected("k:v"))?;
+    let (k, v) = match attr.find(':') {
+        Some(i) => (&attr[..i], &attr[i + 1..]),
+        None => return Err(CliParseError::MissingExpected(":"))
+    };
+    let invalid = || CliParseError::InvalidValue(k.to_owned(), v.to_owned());
+    match k {
+        "status" => match v {
+            "online" => Ok(CliCommand::SetStatus(UserStatus::Online)),
+            "offline" => Ok(CliCommand::SetStatus(UserStatus::Offline)),
+            "invisible" => Ok(CliCommand::SetStatus(UserStatus::Invisible)),
+            _ => Err(invalid())
+        },
+        "visibility" => match v {
+            "private" => Ok(CliCommand::SetVisibility(UserVisibility::Private)),
+            "friends" => Ok(CliCommand::SetVisibility(UserVisibility::FriendsOnly)),
+            "public" => Ok(CliCommand::SetVisibility(UserVisibility::Public)),
+            _ => Err(invalid())
+        },
+        _ => Err(CliParseError::NotImpl)
+    }
 }
 
 // todo
@@ -87,6 +248,10 @@ pub enum CliParseError {
     UnrecognizedCommand(String),
     TypeError(TypeId),
     MissingExpected(&'static str),
+    /// Known attribute, value it can't take.
+    InvalidValue(String, String),
+    /// Not something `parse_when` understands, or an interval that ends before it starts.
+    InvalidDate(String),
     NotImpl
 }
 
@@ -116,11 +281,32 @@ Commands may not be chained together.
 /u ::= {user: string}           switch to dm {user}
 /r ::= {k: string}              read an attribute
 /s ::= {k: string}:{v}          set an attribute
+       status:{online | offline | invisible}    how others see us
+       visibility:{private | friends | public}  who sees whether we are online
 /q ::= {query}                  query
+/accept ::= {user: uint}        trust the changed key of {user}
+/verify ::= {user: uint} |      show the safety number shared with {user}
+            {user: uint} confirm    mark {user} as verified after comparing it
+/security ::= |                 show recent security events
+              {count: uint}     show the last {count} security events
+/history ::= |                  show the last day of messages with the targeted user
+             since {t} |        show messages since {t}
+             from {t} to {t}    show messages between two points in time
+/groups                         list the groups of the account
+/group ::= create |             create a group
+           create {motd} |      create a group with a message of the day
+           {gid} invite {user: uint} |  add {user} to group {gid}
+           {gid} leave |        leave group {gid}
+           {gid} members |      list the members of group {gid}
+           {gid} motd |         clear the message of the day of group {gid}
+           {gid} motd {motd}    set the message of the day of group {gid}
 /{..}                           unrecognized command, will not be sent
 {text}                          send {text} to currently active destination
 ```
 
+`{t}` is `now`, `today`, `yesterday`, an age (`30m`, `2h`, `3d`, `1w`), a date (`2021-03-14`)
+or an RFC 3339 timestamp. Days start at local midnight.
+
 # Types
 
 Strings do not require surrounding codes.
@@ -140,7 +326,30 @@ pub enum CliCommand {
         web_port: Option<u16>
     },
     SelectGroup(GroupId),
-    SelectUser(UserId)
+    SelectUser(UserId),
+    AcceptKey(UserId),
+    Verify {
+        uid: UserId,
+        confirm: bool
+    },
+    ShowSecurityLog(usize),
+    History(HistoryQuery),
+    ListGroups,
+    CreateGroup {
+        motd: Option<String>
+    },
+    InviteToGroup {
+        gid: GroupId,
+        uid: UserId
+    },
+    LeaveGroup(GroupId),
+    ListMembers(GroupId),
+    SetMotd {
+        gid: GroupId,
+        motd: Option<String>
+    },
+    SetStatus(UserStatus),
+    SetVisibility(UserVisibility)
 }
 
 pub enum CliType {
@@ -155,4 +364,39 @@ pub enum CliType {
 pub enum ClientState {
     Disconnected,
     Connected
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::FixedOffset;
+
+    // a fixed offset, so the result doesn't depend on the machine's timezone and its DST changes
+    fn tz() -> FixedOffset {
+        FixedOffset::east(3600)
+    }
+
+    fn at(tk: &str) -> Option<DateTime<FixedOffset>> {
+        let now = tz().ymd(2021, 3, 14).and_hms(15, 0, 0);
+        parse_when(tk, now).map(|t| t.with_timezone(&tz()))
+    }
+
+    #[test]
+    fn relative_dates() {
+        assert_eq!(at("2h"), Some(tz().ymd(2021, 3, 14).and_hms(13, 0, 0)));
+        assert_eq!(at("1w"), Some(tz().ymd(2021, 3, 7).and_hms(15, 0, 0)));
+        assert_eq!(at("yesterday"), Some(tz().ymd(2021, 3, 13).and_hms(0, 0, 0)));
+        assert_eq!(at("2021-01-02"), Some(tz().ymd(2021, 1, 2).and_hms(0, 0, 0)));
+        assert_eq!(at("2021-03-01T12:00:00Z"), Some(tz().ymd(2021, 3, 1).and_hms(13, 0, 0)));
+        assert!(at("2x").is_none());
+        assert!(at("-2h").is_none());
+        assert!(at("").is_none());
+    }
+
+    #[test]
+    fn odd_input_does_not_panic() {
+        assert!(at("3é").is_none());
+        assert!(at("é").is_none());
+        assert!(at("999999999999999999w").is_none());
+        assert!(at("9223372036854775807m").is_none());
+    }
+}
@@ -37,6 +37,49 @@ mod shared {
         pub groups: Option<Vec<GroupId>>,
         pub motd: Option<String>,
         pub online: bool,
+        /// Set once the user rotated their key, for contacts who pinned the previous one.
+        #[serde(default)]
+        pub key_change: Option<KeyChangeNotice>,
+        /// Keys of further devices linked to the account, messages are encrypted to each of them.
+        #[serde(default)]
+        pub devices: Vec<DeviceKey>,
+    }
+
+    /// Published by a user who replaced their key. Signed by the **old** private key,
+    /// so contacts who trusted it can trust the new one.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct KeyChangeNotice {
+        pub old_pubkey: Pubkey,
+        pub new_pubkey: Pubkey,
+        pub time: DateTime<Utc>,
+        pub signature: String,
+    }
+
+    /// Key of a linked device. Signed by the account's main key, so the server can't add devices.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct DeviceKey {
+        pub pubkey: Pubkey,
+        pub name: String,
+        pub added: DateTime<Utc>,
+        pub signature: String,
+    }
+
+    /// Posted by a device asking to be linked, until the main device approves it with a `DeviceKey`.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct PendingDevice {
+        pub pubkey: Pubkey,
+        pub name: String,
+        /// Proves the device saw the pairing code, see `PairingCode`.
+        pub mac: String,
+    }
+
+    /// Posted by the main device for a new one to fetch before it can log in, see `PairingCode`.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct PairingOffer {
+        /// Derived from the pairing code, see `PairingCode::offer_id`.
+        pub id: String,
+        /// Hex `LinkOffer`, encrypted with a key from the pairing code.
+        pub sealed: String,
     }
 
     #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -48,10 +91,24 @@ mod shared {
         pub content: ClientMessage,
     }
 
+    /// Group counterpart of `PublicUserMessage`. `content` is sealed with the sender's sender key
+    /// for the group, see `GroupKeys`.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct PublicGroupMessage {
+        pub gmid: GroupMessageId,
+        pub from: UserId,
+        pub to: GroupId,
+        pub time_posted: DateTime<Utc>,
+        pub content: ClientMessage,
+    }
+
     #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct GroupRecord {
         pub gid: GroupId,
         pub motd: Option<String>,
+        /// Only sent to members.
+        #[serde(default)]
+        pub members: Vec<UserId>,
     }
 
     #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -110,7 +167,7 @@ mod shared {
         }
     }
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq, Copy)]
     pub struct GroupId(u32);
 
     impl Display for GroupId {
@@ -125,7 +182,7 @@ mod shared {
         }
     }
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
     pub struct UserMessageId(u64);
 
     impl Display for UserMessageId {
@@ -190,11 +247,24 @@ mod shared {
             && value.chars().count() == len
     }
 
-    #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Debug)]
+    /// Authenticates requests as the logged in user. Redacted in `Debug` and zeroed when dropped.
+    #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
     pub struct LoginToken {
         pub tk: String,
     }
 
+    impl std::fmt::Debug for LoginToken {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "LoginToken(<redacted>)")
+        }
+    }
+
+    impl Drop for LoginToken {
+        fn drop(&mut self) {
+            zeroize::Zeroize::zeroize(&mut self.tk);
+        }
+    }
+
     impl LoginToken {
         pub fn new() -> LoginToken {
             LoginToken {
@@ -219,13 +289,22 @@ mod shared {
         }
     }
 
-    #[derive(Serialize, Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
     pub enum MessageActor {
         Dm(UserId),
         Group(GroupId),
     }
 
-    #[derive(Serialize, Deserialize)]
+    impl Display for MessageActor {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                MessageActor::Dm(uid) => write!(f, "user {}", uid),
+                MessageActor::Group(gid) => write!(f, "group {}", gid),
+            }
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     pub enum HistoryQuery {
         Unseen,
         Interval {
@@ -235,6 +314,89 @@ mod shared {
         Since(DateTime<Utc>),
     }
 
+    /// Version of the WebSocket protocol spoken by this client. Bump whenever a payload
+    /// changes in a way older peers can't handle.
+    pub const PROTOCOL_VERSION: u32 = 1;
+    /// Oldest protocol version this client can still work with.
+    pub const MIN_PROTOCOL_VERSION: u32 = 1;
+    /// Features this client supports, announced in `Hello`.
+    pub const CLIENT_CAPABILITIES: &[&str] = &["e2e-dm", "ratchet", CAP_GROUPS, "devices", CAP_HISTORY, CAP_PRESENCE];
+    /// Relays `WsServerboundPayload::NewGroupMessage` to members and serves `GroupRecord`s.
+    pub const CAP_GROUPS: &str = "groups";
+    /// Sends `WsClientboundPayload::PresenceChanged` for users the client may see.
+    pub const CAP_PRESENCE: &str = "presence";
+    /// Answers `WsServerboundPayload::History` with `WsClientboundPayload::History`.
+    pub const CAP_HISTORY: &str = "history";
+
+    /// Exchanged right after connecting, `Hello` from the client and `Welcome` from the server.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct ProtocolInfo {
+        pub version: u32,
+        pub min_version: u32,
+        /// Unknown ones are ignored, so either side can add features without a version bump.
+        #[serde(default)]
+        pub capabilities: Vec<String>,
+    }
+
+    #[derive(Debug)]
+    pub enum ProtocolError {
+        Incompatible { ours: ProtocolInfo, theirs: ProtocolInfo },
+    }
+
+    impl Display for ProtocolError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                ProtocolError::Incompatible { ours, theirs } => write!(
+                    f,
+                    "server speaks protocol {}-{}, this client {}-{}, update the {}",
+                    theirs.min_version,
+                    theirs.version,
+                    ours.min_version,
+                    ours.version,
+                    if theirs.min_version > ours.version { "client" } else { "server" }
+                ),
+            }
+        }
+    }
+
+    impl Error for ProtocolError {}
+
+    impl ProtocolInfo {
+        pub fn client() -> Self {
+            Self {
+                version: PROTOCOL_VERSION,
+                min_version: MIN_PROTOCOL_VERSION,
+                capabilities: CLIENT_CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            }
+        }
+
+        /// Highest version both sides speak.
+        pub fn negotiate(&self, theirs: &ProtocolInfo) -> Result<u32, ProtocolError> {
+            let version = self.version.min(theirs.version);
+            if version < self.min_version.max(theirs.min_version) {
+                Err(ProtocolError::Incompatible {
+                    ours: self.clone(),
+                    theirs: theirs.clone(),
+                })
+            } else {
+                Ok(version)
+            }
+        }
+
+        pub fn has(&self, capability: &str) -> bool {
+            self.capabilities.iter().any(|c| c == capability)
+        }
+    }
+
+    /// Name of the variant of a payload that didn't parse, e.g. one added by a newer server.
+    pub fn payload_kind(raw: &str) -> Option<String> {
+        match serde_json::from_str::<serde_json::Value>(raw).ok()? {
+            serde_json::Value::String(unit) => Some(unit),
+            serde_json::Value::Object(map) if map.len() == 1 => map.keys().next().cloned(),
+            _ => None,
+        }
+    }
+
     impl<T> From<T> for WsClientboundPayload
     where
         T: ClientboundPayload,
@@ -250,19 +412,77 @@ mod shared {
     pub enum WsClientboundPayload {
         NewMessage(PublicUserMessage),
         NewMessages(Vec<PublicUserMessage>),
+        /// Answer to `WsServerboundPayload::History` with the same `id`.
+        History { id: u64, messages: Vec<PublicUserMessage> },
         MessageSent(UserMessageId),
+        NewGroupMessage(PublicGroupMessage),
+        /// A user went online or offline, as far as their `UserVisibility` lets us know.
+        PresenceChanged { uid: UserId, online: bool },
+        /// Group counterpart of `MessageSent`.
+        GroupMessageSent(GroupMessageId),
+        /// Answer to `WsServerboundPayload::Hello`.
+        Welcome(ProtocolInfo),
     }
     #[derive(Serialize, Deserialize, Debug)]
     pub struct RegisterRequest {
         pub email: String,
-        pub password_hash: String,
+        pub password_hash: SecretString,
         pub pubkey: String,
     }
 
     #[derive(Serialize, Deserialize, Debug)]
     pub struct LoginRequest {
         pub email: String,
-        pub password_hash: String,
+        pub password_hash: SecretString,
+    }
+
+    /// Medium-term X25519 key used to start forward-secret sessions, signed by the owner's
+    /// Ed25519 identity key. Fetched from and uploaded to the server as-is.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct SignedPrekey {
+        /// Hex DER.
+        pub key: String,
+        pub created: DateTime<Utc>,
+        pub signature: String,
+    }
+
+    /// Replaces the public key of the logged in user.
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct PubkeyUpdateRequest {
+        pub pubkey: String,
+        pub notice: KeyChangeNotice,
+    }
+
+    /// Replaces the stored password hash, e.g. when moving to a stronger KDF.
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct ChangePasswordRequest {
+        pub email: String,
+        pub old_password_hash: SecretString,
+        pub new_password_hash: SecretString,
+    }
+    /// Creates a group with the logged in user as its only member, answered with its `GroupRecord`.
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct CreateGroupRequest {
+        pub motd: Option<String>,
+    }
+
+    /// Adds `uid` to a group the logged in user is a member of.
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct GroupInviteRequest {
+        pub uid: UserId,
+    }
+
+    /// Updates how the logged in user appears to others, fields left `None` stay as they are.
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct PresenceUpdateRequest {
+        pub status: Option<UserStatus>,
+        pub visibility: Option<UserVisibility>,
+    }
+
+    /// `None` clears the message of the day.
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct SetMotdRequest {
+        pub motd: Option<String>,
     }
     pub trait ClientboundPayload
     where
@@ -283,9 +503,20 @@ mod shared {
         }
     }
 
-    #[derive(Serialize, Deserialize, Debug)]
+    impl ClientboundPayload for PublicGroupMessage {
+        fn make_payload(self) -> WsClientboundPayload {
+            WsClientboundPayload::NewGroupMessage(self)
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     pub enum WsServerboundPayload {
         NewUserMessage { to: UserId, content: ClientMessage },
+        NewGroupMessage { to: GroupId, content: ClientMessage },
+        /// First thing sent after connecting.
+        Hello(ProtocolInfo),
+        /// Asks for stored messages, answered with `WsClientboundPayload::History` carrying the same `id`.
+        History { id: u64, query: HistoryQuery },
     }
 
     impl Into<tungstenite::Message> for WsServerboundPayload {
@@ -294,6 +525,7 @@ mod shared {
         }
     }
 
+    /// Opaque encrypted message. The client puts a versioned envelope in here, the server only stores it.
     #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct ClientMessage(String);
 
@@ -0,0 +1,244 @@
+use crate::imports::*;
+use crate::symbols::*;
+
+use qrcode::{render::unicode::Dense1x2, QrCode};
+
+/// How long `approve-device` waits for the new device.
+pub const PAIRING_VALID_MINUTES: i64 = 10;
+/// Short enough to type, still far too many to guess while a code is valid.
+const PAIRING_SECRET_LEN: usize = 10;
+/// Hex digits of the main key's fingerprint in a pairing code.
+const PAIRING_PRIMARY_LEN: usize = 16;
+
+/// Shown by the main device as a QR or text code and entered on the new device, e.g.
+/// `1f2e-3d4c-5b6a-7988-0a1b-3f9a-1c2b-7d4e-5f60`: a random secret followed by the start of the
+/// fingerprint of the account's main key.
+///
+/// Everything else the new device needs travels through the server as a `PairingOffer`, sealed with
+/// a key derived from the secret. The secret itself never reaches the server: the new device proves
+/// it saw the code with a MAC over its key (see `PendingDevice::mac`), so the server can't slip in a
+/// key of its own.
+pub struct PairingCode {
+    secret: SecretBytes,
+    /// Start of the fingerprint of the account's main key, checked against what the server publishes.
+    primary: String,
+}
+
+/// What the new device needs to log in, sealed into a `PairingOffer`.
+/// The server address is the one thing the new device is told by the user.
+#[derive(Serialize, Deserialize)]
+pub struct LinkOffer {
+    pub ws_addr: String,
+    pub email: String,
+    /// Logging in needs the same salt as the main device.
+    pub kdf: PasswordKdf,
+    pub expires: DateTime<Utc>,
+}
+
+impl LinkOffer {
+    pub fn new(cfg: &LocalServerEntry) -> Self {
+        Self {
+            ws_addr: cfg.ws_addr.clone(),
+            email: cfg.email.clone(),
+            kdf: cfg.kdf.clone(),
+            expires: Utc::now() + chrono::Duration::minutes(PAIRING_VALID_MINUTES),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Utc::now() > self.expires
+    }
+}
+
+impl PairingCode {
+    pub fn new(key: &InMemoryKey) -> Result<Self, CryptoError> {
+        Ok(Self {
+            secret: SecretBytes::new(random_bytes(PAIRING_SECRET_LEN)?),
+            primary: key.fingerprint()[..PAIRING_PRIMARY_LEN].to_owned(),
+        })
+    }
+
+    /// Hex digits in groups of four.
+    pub fn to_text(&self) -> String {
+        let digits = format!("{}{}", hex::encode(&*self.secret), self.primary);
+        digits
+            .as_bytes()
+            .chunks(4)
+            .map(|group| std::str::from_utf8(group).unwrap())
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    /// Reads what `to_text` wrote, dashes, whitespace and case are ignored.
+    pub fn parse(text: &str) -> Result<Self, CryptoError> {
+        let digits: String = text
+            .chars()
+            .filter(|c| *c != '-' && !c.is_whitespace())
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        if digits.len() != PAIRING_SECRET_LEN * 2 + PAIRING_PRIMARY_LEN {
+            return Err(CryptoError::Decode("pairing code".to_owned()));
+        }
+        let (secret, primary) = digits.split_at(PAIRING_SECRET_LEN * 2);
+        unhex("pairing code", primary)?;
+        Ok(Self {
+            secret: SecretBytes::new(unhex("pairing code", secret)?),
+            primary: primary.to_owned(),
+        })
+    }
+
+    /// `to_text` as a QR code drawn with block characters, `None` if it doesn't fit in one.
+    pub fn to_qr(&self) -> Option<String> {
+        let code = QrCode::new(self.to_text().as_bytes()).ok()?;
+        Some(code.render::<Dense1x2>().quiet_zone(true).build())
+    }
+
+    /// Whether `fingerprint`, of the main key the server publishes, is the one the code was made with.
+    pub fn is_primary(&self, fingerprint: &str) -> bool {
+        fingerprint.starts_with(&self.primary)
+    }
+
+    /// Where the offer is kept on the server. Doesn't tell anything about the secret.
+    pub fn offer_id(&self) -> Result<String, CryptoError> {
+        Ok(hex::encode(hmac_sha256(&self.secret, b"yap link offer id")?))
+    }
+
+    pub fn seal_offer(&self, offer: &LinkOffer) -> Result<PairingOffer, CryptoError> {
+        let id = self.offer_id()?;
+        let nonce = random_bytes(AEAD_NONCE_LEN)?;
+        let json = serde_json::to_vec(offer).map_err(|_| CryptoError::Decode("link offer".to_owned()))?;
+        let mut sealed = nonce.clone();
+        sealed.extend(seal_aead(&self.offer_key()?, &nonce, id.as_bytes(), &json)?);
+        Ok(PairingOffer {
+            id,
+            sealed: hex::encode(sealed),
+        })
+    }
+
+    /// Fails like a bad tag if the offer wasn't sealed with this code.
+    pub fn open_offer(&self, offer: &PairingOffer) -> Result<LinkOffer, CryptoError> {
+        let sealed = unhex("link offer", &offer.sealed)?;
+        if sealed.len() < AEAD_NONCE_LEN {
+            return Err(CryptoError::PaddingOrAuth);
+        }
+        let (nonce, ciphertext) = sealed.split_at(AEAD_NONCE_LEN);
+        let json = open_aead(&self.offer_key()?, nonce, self.offer_id()?.as_bytes(), ciphertext)?;
+        serde_json::from_slice(&json).map_err(|_| CryptoError::Decode("link offer".to_owned()))
+    }
+
+    fn offer_key(&self) -> Result<SecretBytes, CryptoError> {
+        Ok(SecretBytes::new(hmac_sha256(&self.secret, b"yap link offer key")?))
+    }
+
+    /// What the new device posts to the server, see `post_pending_device`.
+    pub fn request(&self, pubkey: &Pubkey, name: &str) -> Result<PendingDevice, CryptoError> {
+        Ok(PendingDevice {
+            pubkey: pubkey.clone(),
+            name: name.to_owned(),
+            mac: hex::encode(self.mac(pubkey, name)?),
+        })
+    }
+
+    /// Whether `pending` comes from a device that saw this code.
+    pub fn matches(&self, pending: &PendingDevice) -> bool {
+        match (self.mac(&pending.pubkey, &pending.name), hex::decode(&pending.mac)) {
+            (Ok(expected), Ok(mac)) => expected.len() == mac.len() && openssl::memcmp::eq(&expected, &mac),
+            _ => false,
+        }
+    }
+
+    fn mac(&self, pubkey: &Pubkey, name: &str) -> Result<Vec<u8>, CryptoError> {
+        let data = format!("yap link device\n{}\n{}\n{}", self.primary, pubkey.to_string(), name);
+        hmac_sha256(&self.secret, data.as_bytes())
+    }
+}
+
+/// Vouches for `pending` with the account's main key.
+pub fn sign_device(key: &InMemoryKey, pending: &PendingDevice) -> Result<DeviceKey, CryptoError> {
+    PublicIdentity::parse(&pending.pubkey)?.validate()?;
+    let mut device = DeviceKey {
+        pubkey: pending.pubkey.clone(),
+        name: pending.name.clone(),
+        added: Utc::now(),
+        signature: String::new(),
+    };
+    device.signature = hex::encode(key.private().sign(&device_bytes(&device))?);
+    Ok(device)
+}
+
+fn device_bytes(device: &DeviceKey) -> Vec<u8> {
+    format!(
+        "yap device key\n{}\n{}\n{}",
+        device.pubkey.to_string(),
+        device.name,
+        device.added.to_rfc3339()
+    )
+    .into_bytes()
+}
+
+impl DeviceKey {
+    /// Checks that `primary` signed off on this device and returns its parsed key.
+    pub fn verify(&self, primary: &PublicIdentity) -> Result<PublicIdentity, CryptoError> {
+        let signature = unhex("signature", &self.signature)?;
+        if !primary.verify(&device_bytes(self), &signature) {
+            return Err(CryptoError::PaddingOrAuth);
+        }
+        let identity = PublicIdentity::parse(&self.pubkey)?;
+        identity.validate()?;
+        Ok(identity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn main_key() -> InMemoryKey {
+        let identity = LocalIdentity::generate(KeyAlgorithm::Curve25519, "passphrase").unwrap();
+        InMemoryKey::unlock(&identity, &[], "passphrase").unwrap()
+    }
+
+    fn offer() -> LinkOffer {
+        LinkOffer {
+            ws_addr: "ws://localhost:8080/ws".to_owned(),
+            email: "alice@example.com".to_owned(),
+            kdf: PasswordKdf::generate().unwrap(),
+            expires: Utc::now() + chrono::Duration::minutes(PAIRING_VALID_MINUTES),
+        }
+    }
+
+    #[test]
+    fn code_text_is_short_and_round_trips() {
+        let key = main_key();
+        let code = PairingCode::new(&key).unwrap();
+        let text = code.to_text();
+        assert_eq!(text.len(), 44);
+        let typed = PairingCode::parse(&format!(" {}\n", text.replace('-', " ").to_uppercase())).unwrap();
+        assert_eq!(typed.to_text(), text);
+        assert!(typed.is_primary(key.fingerprint()));
+        assert!(!typed.is_primary(main_key().fingerprint()));
+        assert!(PairingCode::parse(&text[..text.len() - 1]).is_err());
+        assert!(PairingCode::parse(&"g".repeat(36)).is_err());
+    }
+
+    #[test]
+    fn offer_only_opens_with_its_code() {
+        let key = main_key();
+        let code = PairingCode::new(&key).unwrap();
+        let sealed = code.seal_offer(&offer()).unwrap();
+        assert_eq!(sealed.id, code.offer_id().unwrap());
+        let typed = PairingCode::parse(&code.to_text()).unwrap();
+        let opened = typed.open_offer(&sealed).unwrap();
+        assert_eq!(opened.email, "alice@example.com");
+        assert!(!opened.is_expired());
+        let other = PairingCode::new(&key).unwrap();
+        assert_ne!(other.offer_id().unwrap(), sealed.id);
+        assert!(matches!(other.open_offer(&sealed), Err(CryptoError::PaddingOrAuth)));
+        // moved to another id by the server
+        let moved = PairingOffer {
+            id: other.offer_id().unwrap(),
+            sealed: sealed.sealed.clone(),
+        };
+        assert!(other.open_offer(&moved).is_err());
+    }
+}
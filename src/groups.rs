@@ -0,0 +1,332 @@
+use crate::imports::*;
+use crate::symbols::*;
+
+/// `version` of a `GroupMessage`, see `ENVELOPE_VERSION` for the ones taken by envelopes and
+/// ratchet messages. 4 was the format before plaintexts were padded.
+pub const GROUP_VERSION: u8 = 7;
+/// Most message keys skipped in one go, e.g. for group messages the server never delivered.
+const MAX_GROUP_SKIP: u32 = 1000;
+/// Skipped message keys kept per sender key before the oldest are dropped.
+const MAX_GROUP_SKIPPED_KEYS: usize = 2000;
+/// Sender keys kept per member, messages sent right before a rotation may still arrive.
+const SENDER_KEYS_KEPT: usize = 3;
+
+/// A member's sender key for a group, handed to every other member in an encrypted DM.
+/// Anyone holding it can read messages from `iteration` on, but not earlier ones.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SenderKeyDistribution {
+    pub group: GroupId,
+    pub key_id: String,
+    /// Hex.
+    pub chain_key: String,
+    pub iteration: u32,
+}
+
+/// Sent as the body of an encrypted DM sealed as `PayloadKind::Control`, handled without being shown.
+#[derive(Serialize, Deserialize)]
+pub enum DmControl {
+    SenderKey(SenderKeyDistribution),
+    /// Asks a member for their sender key again, e.g. after the DM with it was lost.
+    /// Only answered for current members, see `GroupKeys::distribution`.
+    SenderKeyRequest(GroupId),
+}
+
+impl DmControl {
+    /// `None` if it isn't one this client understands, e.g. from a newer version.
+    pub fn parse(plaintext: &str) -> Option<Self> {
+        serde_json::from_str(plaintext).ok()
+    }
+
+    pub fn to_text(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// What travels inside a `ClientMessage` sent to a group, serialized as JSON.
+/// Signed with the sender's identity key, so members can't impersonate each other.
+#[derive(Serialize, Deserialize)]
+struct GroupMessage {
+    version: u8,
+    key_id: String,
+    iteration: u32,
+    ciphertext: String,
+    signature: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct SkippedGroupKey {
+    iteration: u32,
+    mk: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct SenderChain {
+    key_id: String,
+    chain_key: String,
+    /// Iteration of the next message key.
+    iteration: u32,
+    #[serde(default)]
+    skipped: Vec<SkippedGroupKey>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct OwnSenderKey {
+    chain: SenderChain,
+    /// Who the key was handed to, a different set of members means a new key.
+    members: Vec<UserId>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct GroupData {
+    own: HashMap<GroupId, OwnSenderKey>,
+    /// Newest first per member.
+    received: HashMap<GroupId, HashMap<UserId, Vec<SenderChain>>>,
+}
+
+/// End-to-end encryption for groups with sender keys.
+///
+/// Every member has their own hash chain per group and hands its current state to the other
+/// members over DM (see `DmControl`), so sending costs one encryption regardless of group size.
+/// The key is replaced whenever membership changes: new members can't read what was sent before
+/// they joined, and removed members can't read what is sent after they left.
+/// Kept sealed next to the config, which needs a curve identity like `Sessions`.
+pub struct GroupKeys {
+    path: PathBuf,
+    /// `None` for RSA identities.
+    storage_key: Option<SecretBytes>,
+    data: GroupData,
+}
+
+impl GroupKeys {
+    /// Kept next to the config, e.g. `alice.json` -> `alice.group_keys.json`.
+    pub fn path_for(cfg_path: &Path) -> PathBuf {
+        cfg_path.with_extension("group_keys.json")
+    }
+
+    pub fn load(path: PathBuf, key: &InMemoryKey) -> Result<Self, ConfigError> {
+        let storage_key = key.private().storage_key(b"yap group key store").unwrap_or_else(|e| {
+            error!("Cannot derive group key storage key, group encryption disabled: {}", e);
+            None
+        });
+        let mut data = GroupData::default();
+        if let Some(sk) = &storage_key {
+            if path.exists() {
+                let sealed: SealedState = load_private_json(&path)?;
+                match sealed.open(sk, "group keys") {
+                    Ok(d) => data = d,
+                    Err(e) => {
+                        warn!("Cannot read group keys in {:?}, starting over: {}", &path, e);
+                    }
+                }
+            }
+        }
+        Ok(Self {
+            path,
+            storage_key,
+            data,
+        })
+    }
+
+    pub fn save(&self) -> Result<(), ConfigError> {
+        if let Some(sk) = &self.storage_key {
+            let sealed = SealedState::seal(sk, "group keys", &self.data)
+                .map_err(|e| ConfigError::Io(std::io::Error::other(e.to_string())))?;
+            save_private_json(&self.path, &sealed)?;
+        }
+        Ok(())
+    }
+
+    /// Call before sending to `gid` with its current `members`. Returns a new sender key if there
+    /// was none yet or membership changed, which must reach every other member before `encrypt`.
+    pub fn prepare(&mut self, gid: GroupId, members: &[UserId]) -> Result<Option<SenderKeyDistribution>, CryptoError> {
+        self.check_supported()?;
+        let mut members = members.to_vec();
+        members.sort_by_key(|uid| Into::<u32>::into(*uid));
+        members.dedup();
+        if let Some(own) = self.data.own.get(&gid) {
+            if own.members == members {
+                return Ok(None);
+            }
+        }
+        let chain = SenderChain {
+            key_id: hex::encode(random_bytes(16)?),
+            chain_key: hex::encode(random_bytes(AEAD_KEY_LEN)?),
+            iteration: 0,
+            skipped: Vec::new(),
+        };
+        let dist = SenderKeyDistribution {
+            group: gid,
+            key_id: chain.key_id.clone(),
+            chain_key: chain.chain_key.clone(),
+            iteration: chain.iteration,
+        };
+        self.data.own.insert(gid, OwnSenderKey { chain, members });
+        Ok(Some(dist))
+    }
+
+    /// Own current sender key for `gid`, e.g. for a member who missed it.
+    pub fn distribution(&self, gid: GroupId) -> Option<SenderKeyDistribution> {
+        self.data.own.get(&gid).map(|own| SenderKeyDistribution {
+            group: gid,
+            key_id: own.chain.key_id.clone(),
+            chain_key: own.chain.chain_key.clone(),
+            iteration: own.chain.iteration,
+        })
+    }
+
+    /// Stores a sender key `from` sent us over DM.
+    pub fn accept(&mut self, from: UserId, dist: SenderKeyDistribution) {
+        let chains = self
+            .data
+            .received
+            .entry(dist.group)
+            .or_default()
+            .entry(from)
+            .or_default();
+        chains.retain(|c| c.key_id != dist.key_id);
+        chains.insert(
+            0,
+            SenderChain {
+                key_id: dist.key_id,
+                chain_key: dist.chain_key,
+                iteration: dist.iteration,
+                skipped: Vec::new(),
+            },
+        );
+        chains.truncate(SENDER_KEYS_KEPT);
+    }
+
+    /// Drops everything about `gid`, e.g. after leaving it.
+    pub fn forget(&mut self, gid: GroupId) {
+        self.data.own.remove(&gid);
+        self.data.received.remove(&gid);
+    }
+
+    pub fn encrypt(&mut self, key: &InMemoryKey, gid: GroupId, msg: &str) -> Result<ClientMessage, CryptoError> {
+        self.check_supported()?;
+        if msg.len() > MAX_MESSAGE_LEN {
+            return Err(CryptoError::Oversize(msg.len()));
+        }
+        let own = self.data.own.get_mut(&gid).ok_or(CryptoError::NoSession)?;
+        let (next, mk) = kdf_ck(&unhex("chain key", &own.chain.chain_key)?)?;
+        let iteration = own.chain.iteration;
+        let (content_key, nonce) = message_keys(&mk)?;
+        let ciphertext = seal_aead(
+            &content_key,
+            &nonce,
+            &group_aad(gid, &own.chain.key_id, iteration),
+            &key.padding().pad(msg.as_bytes()),
+        )?;
+        let mut out = GroupMessage {
+            version: GROUP_VERSION,
+            key_id: own.chain.key_id.clone(),
+            iteration,
+            ciphertext: hex::encode(ciphertext),
+            signature: String::new(),
+        };
+        out.signature = hex::encode(key.private().sign(&group_signed_bytes(gid, &out))?);
+        own.chain.chain_key = hex::encode(next);
+        own.chain.iteration += 1;
+        let serialized = serde_json::to_string(&out).map_err(|_| CryptoError::Decode("group message".to_owned()))?;
+        Ok(ClientMessage::from(serialized))
+    }
+
+    pub fn decrypt(&mut self, from: &CachedUser, gid: GroupId, msg: ClientMessage) -> Result<String, CryptoError> {
+        self.check_supported()?;
+        let m: GroupMessage = serde_json::from_str(&msg.to_string())
+            .map_err(|_| CryptoError::Decode("group message".to_owned()))?;
+        if m.version != GROUP_VERSION {
+            return Err(CryptoError::UnsupportedVersion(m.version));
+        }
+        if m.ciphertext.len() / 2 > MAX_PADDED_LEN + AEAD_TAG_LEN {
+            return Err(CryptoError::Oversize(m.ciphertext.len() / 2 - AEAD_TAG_LEN));
+        }
+        let signature = unhex("signature", &m.signature)?;
+        let signed = group_signed_bytes(gid, &m);
+        if !from.identities().any(|i| i.verify(&signed, &signature)) {
+            return Err(CryptoError::PaddingOrAuth);
+        }
+        let chain = self
+            .data
+            .received
+            .get_mut(&gid)
+            .and_then(|members| members.get_mut(&from.uid()))
+            .and_then(|chains| chains.iter_mut().find(|c| c.key_id == m.key_id))
+            .ok_or(CryptoError::NoSession)?;
+        // work on a copy, only a message that decrypts may advance the chain
+        let mut next = chain.clone();
+        let mk = next.message_key(m.iteration)?;
+        let (content_key, nonce) = message_keys(&mk)?;
+        let plaintext = open_aead(
+            &content_key,
+            &nonce,
+            &group_aad(gid, &m.key_id, m.iteration),
+            &unhex("ciphertext", &m.ciphertext)?,
+        )?;
+        *chain = next;
+        String::from_utf8(unpad(plaintext)?).map_err(CryptoError::Utf8)
+    }
+
+    fn check_supported(&self) -> Result<(), CryptoError> {
+        if self.storage_key.is_none() {
+            return Err(CryptoError::Unsupported(
+                "group encryption needs an Ed25519/X25519 identity, run rotate-key",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl SenderChain {
+    /// Message key for `iteration`, remembering the keys skipped on the way.
+    /// Each key is handed out once.
+    fn message_key(&mut self, iteration: u32) -> Result<Vec<u8>, CryptoError> {
+        if iteration < self.iteration {
+            let pos = self
+                .skipped
+                .iter()
+                .position(|k| k.iteration == iteration)
+                .ok_or(CryptoError::AlreadyRead)?;
+            return unhex("message key", &self.skipped.remove(pos).mk);
+        }
+        if iteration - self.iteration > MAX_GROUP_SKIP {
+            return Err(CryptoError::PaddingOrAuth);
+        }
+        let mut ck = unhex("chain key", &self.chain_key)?;
+        while self.iteration < iteration {
+            let (next, mk) = kdf_ck(&ck)?;
+            self.skipped.push(SkippedGroupKey {
+                iteration: self.iteration,
+                mk: hex::encode(mk),
+            });
+            ck = next;
+            self.iteration += 1;
+        }
+        if self.skipped.len() > MAX_GROUP_SKIPPED_KEYS {
+            let excess = self.skipped.len() - MAX_GROUP_SKIPPED_KEYS;
+            self.skipped.drain(..excess);
+        }
+        let (next, mk) = kdf_ck(&ck)?;
+        self.chain_key = hex::encode(next);
+        self.iteration += 1;
+        Ok(mk)
+    }
+}
+
+fn group_aad(gid: GroupId, key_id: &str, iteration: u32) -> Vec<u8> {
+    format!("yap group message\n{}\n{}\n{}", gid, key_id, iteration).into_bytes()
+}
+
+/// Length-prefixed like `Envelope::signed_bytes`, so fields can't be shifted into each other.
+fn group_signed_bytes(gid: GroupId, m: &GroupMessage) -> Vec<u8> {
+    let mut out = vec![m.version];
+    let mut push = |field: &str| {
+        out.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        out.extend_from_slice(field.as_bytes());
+    };
+    push(&gid.to_string());
+    push(&m.key_id);
+    push(&m.iteration.to_string());
+    push(&m.ciphertext);
+    out
+}
@@ -0,0 +1,504 @@
+use crate::imports::*;
+use crate::symbols::*;
+use openssl::{
+    derive::Deriver,
+    error::ErrorStack,
+    hash::{hash, MessageDigest},
+    pkey::{Id, PKey, Private, Public},
+    rand::rand_bytes,
+    rsa::{Padding, Rsa},
+    sign::{RsaPssSaltlen, Signer, Verifier},
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+};
+use serde::de::DeserializeOwned;
+
+/// Prefix of published curve identities: `ed25519+x25519:{signing key}:{key agreement key}`,
+/// both hex encoded SubjectPublicKeyInfo DER. Anything without a known prefix is an RSA PEM,
+/// which is what every account registered before curve identities publishes.
+pub const CURVE25519_PREFIX: &str = "ed25519+x25519:";
+
+pub const AEAD_KEY_LEN: usize = 32;
+pub const AEAD_NONCE_LEN: usize = 12;
+pub const AEAD_TAG_LEN: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyAlgorithm {
+    /// RSA-OAEP for key wrapping, RSA-PSS for signatures. Kept for existing accounts.
+    Rsa,
+    /// Ed25519 for signatures, X25519 for key agreement.
+    #[default]
+    Curve25519,
+}
+
+impl KeyAlgorithm {
+    pub fn of(pubkey: &Pubkey) -> Self {
+        if pubkey.to_string().starts_with(CURVE25519_PREFIX) {
+            Self::Curve25519
+        } else {
+            Self::Rsa
+        }
+    }
+}
+
+/// Content key sealed to the public key whose fingerprint is `fingerprint`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WrappedKey {
+    pub fingerprint: String,
+    pub key: String,
+    /// Hex DER of the ephemeral X25519 key the wrapping key was agreed with. Curve identities only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ephemeral: Option<String>,
+}
+
+/// A user as fetched from the server, with their key parsed and validated once
+/// instead of on every message.
+pub struct CachedUser {
+    pub record: PublicUserRecord,
+    pub identity: PublicIdentity,
+    pub fingerprint: String,
+    /// Linked devices whose `DeviceKey` was signed by `identity`, the rest are dropped.
+    pub devices: Vec<PublicIdentity>,
+}
+
+impl CachedUser {
+    pub fn new(record: PublicUserRecord) -> Result<Self, CryptoError> {
+        let identity = PublicIdentity::parse(&record.pubkey)?;
+        identity.validate()?;
+        let devices = record
+            .devices
+            .iter()
+            .filter_map(|d| match d.verify(&identity) {
+                Ok(device) => Some(device),
+                Err(e) => {
+                    warn!("Ignoring device {:?} of {}: {}", &d.name, record.uid, e);
+                    None
+                }
+            })
+            .collect();
+        Ok(Self {
+            fingerprint: identity.fingerprint()?,
+            record,
+            identity,
+            devices,
+        })
+    }
+
+    pub fn uid(&self) -> UserId {
+        self.record.uid
+    }
+
+    /// Main key first, then linked devices.
+    pub fn identities(&self) -> impl Iterator<Item = &PublicIdentity> {
+        std::iter::once(&self.identity).chain(self.devices.iter())
+    }
+
+    /// Whether `fingerprint` is the main key or one of the linked devices.
+    pub fn has_key(&self, fingerprint: &str) -> bool {
+        self.identities().any(|i| i.fingerprint().ok().as_deref() == Some(fingerprint))
+    }
+}
+
+/// Smallest RSA modulus accepted from other users.
+pub const MIN_RSA_BITS: u32 = 2048;
+
+/// Parsed `Pubkey` of any supported algorithm.
+pub enum PublicIdentity {
+    Rsa(Rsa<Public>),
+    Curve25519 { sign: PKey<Public>, dh: PKey<Public> },
+}
+
+impl PublicIdentity {
+    pub fn parse(pubkey: &Pubkey) -> Result<Self, CryptoError> {
+        let s = pubkey.to_string();
+        match KeyAlgorithm::of(pubkey) {
+            KeyAlgorithm::Rsa => Rsa::public_key_from_pem(s.as_bytes())
+                .map(PublicIdentity::Rsa)
+                .map_err(CryptoError::KeyParse),
+            KeyAlgorithm::Curve25519 => {
+                let mut parts = s[CURVE25519_PREFIX.len()..].split(':');
+                let sign = public_from_hex(parts.next(), Id::ED25519)?;
+                let dh = public_from_hex(parts.next(), Id::X25519)?;
+                if parts.next().is_some() {
+                    return Err(CryptoError::Decode("pubkey".to_owned()));
+                }
+                Ok(PublicIdentity::Curve25519 { sign, dh })
+            }
+        }
+    }
+
+    /// Rejects keys too weak to encrypt to or to trust signatures from.
+    /// Curve keys are checked for the right algorithms by `parse` already.
+    pub fn validate(&self) -> Result<(), CryptoError> {
+        match self {
+            PublicIdentity::Rsa(rsa) => {
+                let bits = rsa.size() * 8;
+                if bits < MIN_RSA_BITS {
+                    return Err(CryptoError::WeakKey(format!("{} bit RSA, at least {} needed", bits, MIN_RSA_BITS)));
+                }
+                let e = rsa.e();
+                if !e.is_bit_set(0) || e.num_bits() < 2 {
+                    return Err(CryptoError::WeakKey(format!("RSA exponent {}", e)));
+                }
+                Ok(())
+            }
+            PublicIdentity::Curve25519 { .. } => Ok(()),
+        }
+    }
+
+    pub fn algorithm(&self) -> KeyAlgorithm {
+        match self {
+            PublicIdentity::Rsa(_) => KeyAlgorithm::Rsa,
+            PublicIdentity::Curve25519 { .. } => KeyAlgorithm::Curve25519,
+        }
+    }
+
+    /// Published form, see `CURVE25519_PREFIX`.
+    pub fn encode(&self) -> Result<Pubkey, CryptoError> {
+        let s = match self {
+            PublicIdentity::Rsa(rsa) => {
+                String::from_utf8(rsa.public_key_to_pem().map_err(CryptoError::Internal)?)
+                    .map_err(CryptoError::Utf8)?
+            }
+            PublicIdentity::Curve25519 { sign, dh } => format!(
+                "{}{}:{}",
+                CURVE25519_PREFIX,
+                hex::encode(sign.public_key_to_der().map_err(CryptoError::Internal)?),
+                hex::encode(dh.public_key_to_der().map_err(CryptoError::Internal)?)
+            ),
+        };
+        Ok(Pubkey::from(s))
+    }
+
+    /// Hex SHA-256 of the DER encoded public key(s), stable across formatting differences.
+    pub fn fingerprint(&self) -> Result<String, CryptoError> {
+        let der = match self {
+            PublicIdentity::Rsa(rsa) => rsa.public_key_to_der(),
+            PublicIdentity::Curve25519 { sign, dh } => sign
+                .public_key_to_der()
+                .and_then(|mut der| {
+                    der.extend(dh.public_key_to_der()?);
+                    Ok(der)
+                }),
+        }
+        .map_err(CryptoError::Internal)?;
+        Ok(hex::encode(hash(MessageDigest::sha256(), &der).map_err(CryptoError::Internal)?))
+    }
+
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        let res: Result<bool, ErrorStack> = match self {
+            PublicIdentity::Rsa(rsa) => PKey::from_rsa(rsa.clone()).and_then(|pkey| {
+                let mut verifier = Verifier::new(MessageDigest::sha256(), &pkey)?;
+                verifier.set_rsa_padding(Padding::PKCS1_PSS)?;
+                verifier.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)?;
+                verifier.update(data)?;
+                verifier.verify(signature)
+            }),
+            PublicIdentity::Curve25519 { sign, .. } => Verifier::new_without_digest(sign)
+                .and_then(|mut verifier| verifier.verify_oneshot(signature, data)),
+        };
+        res.unwrap_or(false)
+    }
+
+    /// Seals `content_key` so only the owner of this identity can recover it.
+    pub fn wrap(&self, content_key: &[u8]) -> Result<WrappedKey, CryptoError> {
+        let fingerprint = self.fingerprint()?;
+        match self {
+            PublicIdentity::Rsa(rsa) => {
+                let mut wrapped = vec![0; rsa.size() as usize];
+                let bytes_written = rsa
+                    .public_encrypt(content_key, &mut wrapped, Padding::PKCS1_OAEP)
+                    .map_err(CryptoError::Internal)?;
+                wrapped.truncate(bytes_written);
+                Ok(WrappedKey {
+                    fingerprint,
+                    key: hex::encode(wrapped),
+                    ephemeral: None,
+                })
+            }
+            PublicIdentity::Curve25519 { dh, .. } => {
+                let ephemeral = PKey::generate_x25519().map_err(CryptoError::Internal)?;
+                let ephemeral_der = ephemeral.public_key_to_der().map_err(CryptoError::Internal)?;
+                let kek = wrapping_key(&ephemeral, dh, &ephemeral_der)?;
+                let wrapped = seal_aead(&kek, &[0; AEAD_NONCE_LEN], fingerprint.as_bytes(), content_key)?;
+                Ok(WrappedKey {
+                    fingerprint,
+                    key: hex::encode(wrapped),
+                    ephemeral: Some(hex::encode(ephemeral_der)),
+                })
+            }
+        }
+    }
+}
+
+fn public_from_hex(part: Option<&str>, id: Id) -> Result<PKey<Public>, CryptoError> {
+    let der = unhex("pubkey", part.ok_or_else(|| CryptoError::Decode("pubkey".to_owned()))?)?;
+    public_from_der(&der, id)
+}
+
+pub fn public_from_der(der: &[u8], id: Id) -> Result<PKey<Public>, CryptoError> {
+    let pkey = PKey::public_key_from_der(der).map_err(CryptoError::KeyParse)?;
+    if pkey.id() != id {
+        return Err(CryptoError::Decode("pubkey".to_owned()));
+    }
+    Ok(pkey)
+}
+
+/// Key encryption key for one `WrappedKey`. The wrapping key is unique per ephemeral key, so a
+/// fixed nonce is fine.
+fn wrapping_key(
+    own: &PKey<Private>,
+    peer: &PKey<Public>,
+    ephemeral_der: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    let shared = x25519(own, peer)?;
+    let mut salt = ephemeral_der.to_vec();
+    salt.extend(peer.public_key_to_der().map_err(CryptoError::Internal)?);
+    hkdf_sha256(&salt, &shared, b"yap content key wrap", AEAD_KEY_LEN)
+}
+
+/// Private half of an identity, decrypted and ready to use.
+/// OpenSSL clears the key material when it is freed.
+pub enum PrivateIdentity {
+    Rsa(Rsa<Private>),
+    Curve25519 { sign: PKey<Private>, dh: PKey<Private> },
+}
+
+impl std::fmt::Debug for PrivateIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrivateIdentity::Rsa(_) => write!(f, "PrivateIdentity::Rsa(<redacted>)"),
+            PrivateIdentity::Curve25519 { .. } => write!(f, "PrivateIdentity::Curve25519(<redacted>)"),
+        }
+    }
+}
+
+impl PrivateIdentity {
+    pub fn generate(algorithm: KeyAlgorithm) -> Result<Self, CryptoError> {
+        match algorithm {
+            KeyAlgorithm::Rsa => Rsa::generate(2048)
+                .map(PrivateIdentity::Rsa)
+                .map_err(CryptoError::Internal),
+            KeyAlgorithm::Curve25519 => Ok(PrivateIdentity::Curve25519 {
+                sign: PKey::generate_ed25519().map_err(CryptoError::Internal)?,
+                dh: PKey::generate_x25519().map_err(CryptoError::Internal)?,
+            }),
+        }
+    }
+
+    /// `privkey`/`dh_privkey` as stored in `LocalIdentity`, PEM. `None` passphrase for plaintext keys.
+    pub fn from_pem(
+        algorithm: KeyAlgorithm,
+        privkey: &str,
+        dh_privkey: Option<&str>,
+        passphrase: Option<&str>,
+    ) -> Result<Self, CryptoError> {
+        let load = |pem: &str| match passphrase {
+            Some(p) => PKey::private_key_from_pem_passphrase(pem.as_bytes(), p.as_bytes())
+                .map_err(|_| CryptoError::Locked),
+            None => PKey::private_key_from_pem(pem.as_bytes()).map_err(CryptoError::KeyParse),
+        };
+        match algorithm {
+            KeyAlgorithm::Rsa => load(privkey)?
+                .rsa()
+                .map(PrivateIdentity::Rsa)
+                .map_err(CryptoError::KeyParse),
+            KeyAlgorithm::Curve25519 => {
+                let sign = load(privkey)?;
+                let dh = load(dh_privkey.ok_or_else(|| CryptoError::Decode("dh_privkey".to_owned()))?)?;
+                if sign.id() != Id::ED25519 || dh.id() != Id::X25519 {
+                    return Err(CryptoError::Decode("privkey".to_owned()));
+                }
+                Ok(PrivateIdentity::Curve25519 { sign, dh })
+            }
+        }
+    }
+
+    /// Encrypted PKCS#8 PEM of the signing key and, for curve identities, the key agreement key.
+    pub fn to_pem(&self, passphrase: &str) -> Result<(String, Option<String>), CryptoError> {
+        let seal = |pkey: &PKey<Private>| {
+            let pem = pkey
+                .private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), passphrase.as_bytes())
+                .map_err(CryptoError::Internal)?;
+            String::from_utf8(pem).map_err(CryptoError::Utf8)
+        };
+        match self {
+            PrivateIdentity::Rsa(rsa) => {
+                let pkey = PKey::from_rsa(rsa.clone()).map_err(CryptoError::Internal)?;
+                Ok((seal(&pkey)?, None))
+            }
+            PrivateIdentity::Curve25519 { sign, dh } => Ok((seal(sign)?, Some(seal(dh)?))),
+        }
+    }
+
+    pub fn public(&self) -> Result<PublicIdentity, CryptoError> {
+        let public = |pkey: &PKey<Private>| {
+            pkey.public_key_to_der()
+                .and_then(|der| PKey::public_key_from_der(&der))
+                .map_err(CryptoError::Internal)
+        };
+        match self {
+            PrivateIdentity::Rsa(rsa) => {
+                let der = rsa.public_key_to_der().map_err(CryptoError::Internal)?;
+                Rsa::public_key_from_der(&der)
+                    .map(PublicIdentity::Rsa)
+                    .map_err(CryptoError::Internal)
+            }
+            PrivateIdentity::Curve25519 { sign, dh } => Ok(PublicIdentity::Curve25519 {
+                sign: public(sign)?,
+                dh: public(dh)?,
+            }),
+        }
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let res = match self {
+            PrivateIdentity::Rsa(rsa) => PKey::from_rsa(rsa.clone()).and_then(|pkey| {
+                let mut signer = Signer::new(MessageDigest::sha256(), &pkey)?;
+                signer.set_rsa_padding(Padding::PKCS1_PSS)?;
+                signer.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)?;
+                signer.update(data)?;
+                signer.sign_to_vec()
+            }),
+            PrivateIdentity::Curve25519 { sign, .. } => {
+                Signer::new_without_digest(sign).and_then(|mut signer| signer.sign_oneshot_to_vec(data))
+            }
+        };
+        res.map_err(CryptoError::Internal)
+    }
+
+    /// Recovers a content key sealed with `PublicIdentity::wrap`.
+    pub fn unwrap(&self, wrapped: &WrappedKey) -> Result<Vec<u8>, CryptoError> {
+        let key = unhex("wrapped key", &wrapped.key)?;
+        let content_key = match self {
+            PrivateIdentity::Rsa(rsa) => {
+                let mut content_key = vec![0; rsa.size() as usize];
+                let bytes_written = rsa
+                    .private_decrypt(&key, &mut content_key, Padding::PKCS1_OAEP)
+                    .map_err(|_| CryptoError::PaddingOrAuth)?;
+                content_key.truncate(bytes_written);
+                content_key
+            }
+            PrivateIdentity::Curve25519 { dh, .. } => {
+                let ephemeral_der = unhex(
+                    "ephemeral key",
+                    wrapped.ephemeral.as_deref().ok_or(CryptoError::PaddingOrAuth)?,
+                )?;
+                let ephemeral = public_from_der(&ephemeral_der, Id::X25519)?;
+                // same derivation as the sender, from the other side
+                let shared = x25519(dh, &ephemeral)?;
+                let mut salt = ephemeral_der;
+                salt.extend(dh.public_key_to_der().map_err(CryptoError::Internal)?);
+                let kek = hkdf_sha256(&salt, &shared, b"yap content key wrap", AEAD_KEY_LEN)?;
+                open_aead(&kek, &[0; AEAD_NONCE_LEN], wrapped.fingerprint.as_bytes(), &key)?
+            }
+        };
+        if content_key.len() != AEAD_KEY_LEN {
+            return Err(CryptoError::PaddingOrAuth);
+        }
+        Ok(content_key)
+    }
+
+    /// Key for sealing local state with `SealedState`, `None` for RSA identities.
+    /// A DH between our own key pair is stable and only we can compute it.
+    pub fn storage_key(&self, purpose: &[u8]) -> Result<Option<SecretBytes>, CryptoError> {
+        match self {
+            PrivateIdentity::Rsa(_) => Ok(None),
+            PrivateIdentity::Curve25519 { dh, .. } => {
+                let der = dh.public_key_to_der().map_err(CryptoError::Internal)?;
+                let own_public = public_from_der(&der, Id::X25519)?;
+                let shared = SecretBytes::new(x25519(dh, &own_public)?);
+                hkdf_sha256(&[], &shared, purpose, AEAD_KEY_LEN).map(|k| Some(SecretBytes::new(k)))
+            }
+        }
+    }
+}
+
+/// Local state at rest, e.g. sessions or group keys, sealed with `PrivateIdentity::storage_key`.
+#[derive(Serialize, Deserialize)]
+pub struct SealedState {
+    nonce: String,
+    ciphertext: String,
+}
+
+impl SealedState {
+    pub fn seal<T: Serialize>(storage_key: &[u8], what: &str, value: &T) -> Result<Self, CryptoError> {
+        let plaintext = serde_json::to_vec(value).map_err(|_| CryptoError::Decode(what.to_owned()))?;
+        let nonce = random_bytes(AEAD_NONCE_LEN)?;
+        let ciphertext = seal_aead(storage_key, &nonce, what.as_bytes(), &plaintext)?;
+        Ok(SealedState {
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        })
+    }
+
+    pub fn open<T: DeserializeOwned>(&self, storage_key: &[u8], what: &str) -> Result<T, CryptoError> {
+        let nonce = unhex("nonce", &self.nonce)?;
+        let ciphertext = unhex("ciphertext", &self.ciphertext)?;
+        let plaintext = open_aead(storage_key, &nonce, what.as_bytes(), &ciphertext)?;
+        serde_json::from_slice(&plaintext).map_err(|_| CryptoError::Decode(what.to_owned()))
+    }
+}
+
+pub fn x25519(own: &PKey<Private>, peer: &PKey<Public>) -> Result<Vec<u8>, CryptoError> {
+    let mut deriver = Deriver::new(own).map_err(CryptoError::Internal)?;
+    deriver.set_peer(peer).map_err(CryptoError::Internal)?;
+    deriver.derive_to_vec().map_err(CryptoError::Internal)
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let pkey = PKey::hmac(key).map_err(CryptoError::Internal)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &pkey).map_err(CryptoError::Internal)?;
+    signer.update(data).map_err(CryptoError::Internal)?;
+    signer.sign_to_vec().map_err(CryptoError::Internal)
+}
+
+/// HKDF (RFC 5869) with SHA-256. An empty `salt` stands for 32 zero bytes, as in the RFC.
+pub fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8], len: usize) -> Result<Vec<u8>, CryptoError> {
+    let prk = if salt.is_empty() {
+        hmac_sha256(&[0; 32], ikm)?
+    } else {
+        hmac_sha256(salt, ikm)?
+    };
+    let mut okm = Vec::with_capacity(len);
+    let mut block = Vec::new();
+    let mut counter = 1u8;
+    while okm.len() < len {
+        let mut input = block;
+        input.extend_from_slice(info);
+        input.push(counter);
+        block = hmac_sha256(&prk, &input)?;
+        okm.extend_from_slice(&block);
+        counter += 1;
+    }
+    okm.truncate(len);
+    Ok(okm)
+}
+
+pub fn random_bytes(len: usize) -> Result<Vec<u8>, CryptoError> {
+    let mut buf = vec![0; len];
+    rand_bytes(&mut buf).map_err(CryptoError::Internal)?;
+    Ok(buf)
+}
+
+/// AES-256-GCM, returns the ciphertext followed by the tag.
+pub fn seal_aead(key: &[u8], nonce: &[u8], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let mut tag = [0; AEAD_TAG_LEN];
+    let mut out = encrypt_aead(Cipher::aes_256_gcm(), key, Some(nonce), aad, data, &mut tag)
+        .map_err(CryptoError::Internal)?;
+    out.extend_from_slice(&tag);
+    Ok(out)
+}
+
+/// Reverses `seal_aead`, failing if anything was tampered with.
+pub fn open_aead(key: &[u8], nonce: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if key.len() != AEAD_KEY_LEN || nonce.len() != AEAD_NONCE_LEN || sealed.len() < AEAD_TAG_LEN {
+        return Err(CryptoError::PaddingOrAuth);
+    }
+    let (data, tag) = sealed.split_at(sealed.len() - AEAD_TAG_LEN);
+    decrypt_aead(Cipher::aes_256_gcm(), key, Some(nonce), aad, data, tag)
+        .map_err(|_| CryptoError::PaddingOrAuth)
+}
+
+pub fn unhex(field: &str, value: &str) -> Result<Vec<u8>, CryptoError> {
+    hex::decode(value.as_bytes()).map_err(|_| CryptoError::Decode(field.to_owned()))
+}
@@ -0,0 +1,175 @@
+use crate::imports::*;
+use crate::symbols::*;
+
+use sha2::{Digest, Sha512};
+
+/// Contacts' public keys as they were first seen (trust on first use), per server.
+///
+/// The server could hand out a different key at any time, so once a key is pinned
+/// a different one is only trusted after the user accepts it.
+#[derive(Serialize, Deserialize, Default)]
+pub struct KnownKeys {
+    #[serde(skip)]
+    path: PathBuf,
+    /// Keyed by the server's http address.
+    servers: HashMap<String, HashMap<UserId, PinnedKey>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PinnedKey {
+    pub fingerprint: String,
+    pub first_seen: DateTime<Utc>,
+    /// Fingerprint of a different key the server offered later, waiting for `/accept`.
+    #[serde(default)]
+    pub offered: Option<String>,
+    /// The user compared safety numbers out of band for this exact key.
+    #[serde(default)]
+    pub verified: bool,
+}
+
+#[derive(Debug)]
+pub enum PinStatus {
+    /// Never seen before, pinned now.
+    New(String),
+    /// Same key as pinned.
+    Match,
+    /// Differs from the pinned key. Nothing should be sent until the user accepts it.
+    Changed { pinned: String, offered: String },
+}
+
+impl KnownKeys {
+    /// Kept next to the config, e.g. `alice.json` -> `alice.known_keys.json`.
+    pub fn path_for(cfg_path: &Path) -> PathBuf {
+        cfg_path.with_extension("known_keys.json")
+    }
+
+    /// A missing file just means nothing has been pinned yet.
+    pub fn load(path: PathBuf) -> Result<Self, ConfigError> {
+        let mut known = if path.exists() {
+            load_private_json::<KnownKeys>(&path)?
+        } else {
+            KnownKeys::default()
+        };
+        known.path = path;
+        Ok(known)
+    }
+
+    pub fn save(&self) -> Result<(), ConfigError> {
+        save_private_json(&self.path, self)
+    }
+
+    /// Compares `pubkey` against the pin for `uid`, pinning it if there is none yet.
+    /// A mismatch is remembered as the offered key so `accept` can promote it.
+    pub fn check(&mut self, server: &str, uid: UserId, pubkey: &Pubkey) -> Result<PinStatus, CryptoError> {
+        let fp = pubkey_fingerprint(pubkey)?;
+        let pins = self.servers.entry(server.to_owned()).or_default();
+        match pins.get_mut(&uid) {
+            None => {
+                pins.insert(
+                    uid,
+                    PinnedKey {
+                        fingerprint: fp.clone(),
+                        first_seen: Utc::now(),
+                        offered: None,
+                        verified: false,
+                    },
+                );
+                Ok(PinStatus::New(fp))
+            }
+            Some(pin) if pin.fingerprint == fp => {
+                pin.offered = None;
+                Ok(PinStatus::Match)
+            }
+            Some(pin) => {
+                pin.offered = Some(fp.clone());
+                Ok(PinStatus::Changed {
+                    pinned: pin.fingerprint.clone(),
+                    offered: fp,
+                })
+            }
+        }
+    }
+
+    /// Replaces the pin for `uid` with the key offered last. Returns the new fingerprint, if there was one.
+    pub fn accept(&mut self, server: &str, uid: UserId) -> Option<String> {
+        let pin = self.servers.get_mut(server)?.get_mut(&uid)?;
+        let offered = pin.offered.take()?;
+        pin.fingerprint = offered.clone();
+        pin.first_seen = Utc::now();
+        // verification was for the old key
+        pin.verified = false;
+        Some(offered)
+    }
+
+    /// Marks the pinned key of `uid` as verified. Refused while a changed key is waiting for `/accept`.
+    pub fn verify(&mut self, server: &str, uid: UserId) -> bool {
+        match self.servers.get_mut(server).and_then(|pins| pins.get_mut(&uid)) {
+            Some(pin) if pin.offered.is_none() => {
+                pin.verified = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_verified(&self, server: &str, uid: UserId) -> bool {
+        self.servers
+            .get(server)
+            .and_then(|pins| pins.get(&uid))
+            .map(|pin| pin.offered.is_none() && pin.verified)
+            .unwrap_or(false)
+    }
+
+    /// Whether messages may be sent to `uid`, i.e. its current key is the pinned one.
+    pub fn is_trusted(&self, server: &str, uid: UserId) -> bool {
+        self.servers
+            .get(server)
+            .and_then(|pins| pins.get(&uid))
+            .map(|pin| pin.offered.is_none())
+            .unwrap_or(false)
+    }
+}
+
+/// Whether `notice` is a valid handover from the `pinned` key to the `offered` one.
+pub fn vouches_for(notice: Option<&KeyChangeNotice>, pinned: &str, offered: &str) -> bool {
+    match notice {
+        Some(notice) => {
+            notice.verify().is_ok()
+                && pubkey_fingerprint(&notice.old_pubkey).ok().as_deref() == Some(pinned)
+                && pubkey_fingerprint(&notice.new_pubkey).ok().as_deref() == Some(offered)
+        }
+        None => false,
+    }
+}
+
+const SAFETY_NUMBER_GROUPS: usize = 12;
+const SAFETY_NUMBER_GROUP_BYTES: usize = 5;
+
+/// Short number both parties can compare out of band, e.g. read out over a call.
+///
+/// Derived from both keys' fingerprints, sorted so both sides compute the same number.
+/// Formatted as 3 rows of 4 groups of 5 digits.
+pub fn safety_number(a: &Pubkey, b: &Pubkey) -> Result<String, CryptoError> {
+    let mut fps = vec![pubkey_fingerprint(a)?, pubkey_fingerprint(b)?];
+    fps.sort();
+    let digest = {
+        let mut hasher = Sha512::new();
+        for fp in &fps {
+            hasher.update(fp.as_bytes());
+        }
+        hasher.finalize()
+    };
+    let groups: Vec<String> = digest
+        .chunks(SAFETY_NUMBER_GROUP_BYTES)
+        .take(SAFETY_NUMBER_GROUPS)
+        .map(|chunk| {
+            let n = chunk.iter().fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+            format!("{:05}", n % 100_000)
+        })
+        .collect();
+    Ok(groups
+        .chunks(4)
+        .map(|row| row.join(" "))
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
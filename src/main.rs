@@ -1,14 +1,34 @@
+mod audit;
 mod auth;
+mod backup;
 mod cli;
 mod common;
+mod devices;
+mod groups;
+mod identity;
+mod keystore;
+mod outbox;
+mod ratchet;
+mod replay;
+mod secret;
 
 #[macro_use]
 extern crate structopt;
 
 pub mod symbols {
+    pub use crate::audit::*;
     pub use crate::auth::*;
+    pub use crate::backup::*;
     pub use crate::cli::*;
     pub use crate::common::*;
+    pub use crate::devices::*;
+    pub use crate::groups::*;
+    pub use crate::identity::*;
+    pub use crate::keystore::*;
+    pub use crate::outbox::*;
+    pub use crate::ratchet::*;
+    pub use crate::replay::*;
+    pub use crate::secret::*;
     //pub use crate::ui::*;
 }
 
@@ -16,7 +36,8 @@ pub mod imports {
     pub use chrono::{DateTime, Utc};
     pub use crossbeam::channel;
     pub use futures::{SinkExt, Stream, StreamExt, TryFutureExt};
-    pub use hashbrown::HashMap;
+    pub use hashbrown::{HashMap, HashSet};
+    pub use std::collections::VecDeque;
 
     pub use log::{debug, error, info, warn};
     pub use rand::{rngs::ThreadRng, Rng};
@@ -37,13 +58,6 @@ use crate::imports::*;
 use crate::symbols::*;
 
 use log::LevelFilter;
-use openssl::rsa::Rsa;
-use sha2::{Digest, Sha256};
-use std::{
-    convert::TryFrom,
-    fs::File,
-    io::{BufReader, BufWriter},
-};
 use structopt::StructOpt;
 
 #[tokio::main]
@@ -55,40 +69,486 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let opt = LaunchOptions::from_args();
     match opt {
         LaunchOptions::Login { cfg_path } => {
-            let f = File::open(&cfg_path)?;
-            let mut buf = BufReader::new(f);
-            let cfg = serde_json::from_reader(buf)?;
+            let mut cfg = LocalServerEntry::load(&cfg_path)?;
             info!("Loaded config file");
-            connect(cfg).await;
+            let known = KnownKeys::load(KnownKeys::path_for(&cfg_path))?;
+            let mut log = SecurityLog::load(SecurityLog::path_for(&cfg_path))?;
+            let password = prompt_password(&cfg)?;
+            let mut key = match InMemoryKey::unlock(&cfg.identity, &cfg.keyring, password.expose()) {
+                Ok(key) => key,
+                Err(e) => {
+                    log.record(SecurityEvent::LoginFailed(e.to_string()));
+                    return Err(e.into());
+                }
+            };
+            key.set_padding(cfg.padding);
+            let phash = cfg.kdf.derive(password.expose())?;
+            if !cfg.identity.is_encrypted() {
+                // old config, stop storing the key in plaintext
+                if cfg.phash.as_ref() != Some(&phash) {
+                    error!("Wrong password");
+                    log.record(SecurityEvent::LoginFailed("wrong password".to_owned()));
+                    return Ok(());
+                }
+                cfg.identity.protect(password.expose())?;
+                cfg.phash = None;
+                cfg.save(&cfg_path)?;
+                info!("Encrypted private key in {:?}", &cfg_path);
+            }
+            let client = reqwest::Client::new();
+            let mut result = login(&cfg, &client, phash).await;
+            let retry_kdf = match &result {
+                Err(LoginError::Rejected(_)) => cfg.pending_kdf.clone(),
+                _ => None,
+            };
+            if let Some(kdf) = retry_kdf {
+                // the server may have switched with only the answer to the upgrade lost
+                result = login(&cfg, &client, kdf.derive(password.expose())?).await;
+                if result.is_ok() {
+                    cfg.kdf = kdf;
+                    cfg.pending_kdf = None;
+                    cfg.save(&cfg_path)?;
+                    info!("Upgraded password hashing to Argon2id");
+                }
+            }
+            let lt = match result {
+                Ok(lt) => lt,
+                Err(e) => {
+                    error!("Failed to login: {:?}", e);
+                    log.record(SecurityEvent::LoginFailed(format!("{:?}", e)));
+                    return Ok(());
+                }
+            };
+            log.record(SecurityEvent::LoginSucceeded);
+            if cfg.kdf.is_legacy() {
+                let kdf = match cfg.pending_kdf.clone() {
+                    Some(kdf) => kdf,
+                    None => {
+                        // save before sending, losing the salt after the server switched would lock the account
+                        let kdf = PasswordKdf::generate()?;
+                        cfg.pending_kdf = Some(kdf.clone());
+                        cfg.save(&cfg_path)?;
+                        kdf
+                    }
+                };
+                match upgrade_kdf(&cfg, &client, &lt, &kdf, password.expose()).await {
+                    Ok(()) => {
+                        cfg.kdf = kdf;
+                        cfg.pending_kdf = None;
+                        cfg.save(&cfg_path)?;
+                        info!("Upgraded password hashing to Argon2id");
+                    }
+                    Err(KdfUpgradeError::Rejected(status)) => {
+                        // the server kept the old hash, so there is nothing to fall back to
+                        cfg.pending_kdf = None;
+                        cfg.save(&cfg_path)?;
+                        warn!("Still using unsalted password hashing, the server refused the upgrade: {}", status);
+                    }
+                    Err(e) => {
+                        warn!("Still using unsalted password hashing: {:?}", e);
+                    }
+                }
+            }
+            let mut linked = cfg.device.is_some();
+            match get_self(&cfg, &client, &lt)
+                .await
+                .and_then(|me| CachedUser::new(me).map_err(GetUserError::InvalidKey))
+            {
+                Ok(me) if !me.has_key(key.fingerprint()) && cfg.device.is_some() => {
+                    error!("This device is not linked to the account yet, approve it on the main device");
+                    cfg.uid = None;
+                }
+                Ok(me) if !me.has_key(key.fingerprint()) && cfg.pending_key_change.is_some() => {
+                    error!("The server doesn't have the key made by rotate-key yet, run rotate-key again to upload it");
+                    cfg.uid = None;
+                }
+                Ok(me) if !me.has_key(key.fingerprint()) => {
+                    error!("The server has a different public key on record for this account, sending is disabled");
+                    log.record(SecurityEvent::OwnKeyMismatch);
+                    cfg.uid = None;
+                }
+                Ok(me) if cfg.uid.is_some() && cfg.uid != Some(me.uid()) => {
+                    error!("The server claims this account is user {} but it was {}, sending is disabled", me.uid(), cfg.uid.unwrap());
+                    log.record(SecurityEvent::OwnUidMismatch {
+                        stored: cfg.uid.unwrap(),
+                        claimed: me.uid(),
+                    });
+                    cfg.uid = None;
+                }
+                Ok(me) => {
+                    linked |= !me.devices.is_empty();
+                    if let Some(notice) = cfg.pending_key_change.take() {
+                        // rotate-key's upload went through, only the answer was lost
+                        info!("The server has the key made by rotate-key");
+                        log.record(SecurityEvent::OwnKeyRotated {
+                            old: pubkey_fingerprint(&notice.old_pubkey)?,
+                            new: key.fingerprint().to_owned(),
+                        });
+                        cfg.save(&cfg_path)?;
+                    }
+                    if cfg.uid.is_none() {
+                        cfg.uid = Some(me.uid());
+                        cfg.save(&cfg_path)?;
+                    }
+                }
+                Err(e) => {
+                    warn!("Cannot look up own user id: {:?}", e);
+                }
+            }
+            let replay = ReplayGuard::load(ReplayGuard::path_for(&cfg_path))?;
+            let mut sessions = Sessions::load(Sessions::path_for(&cfg_path), &key)?;
+            sessions.set_linked(linked);
+            match sessions.prekey_to_publish(&key) {
+                Ok(Some(prekey)) => match upload_prekey(&cfg, &client, &lt, &prekey).await {
+                    Ok(()) => {
+                        sessions.prekey_published();
+                        info!("Published new signed prekey");
+                    }
+                    Err(e) => {
+                        warn!("Failed to publish signed prekey, new sessions can't be started with us: {:?}", e);
+                    }
+                },
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Cannot create signed prekey: {}", e);
+                }
+            }
+            sessions.save()?;
+            let groups = GroupKeys::load(GroupKeys::path_for(&cfg_path), &key)?;
+            let stores = AccountStores {
+                known,
+                log,
+                sessions,
+                groups,
+                replay,
+            };
+            connect(cfg, client, key, lt, stores).await;
+        }
+        LaunchOptions::RotateKey { cfg_path } => {
+            let mut cfg = LocalServerEntry::load(&cfg_path)?;
+            if cfg.device.is_some() {
+                error!("Only the main device can replace the account's key");
+                return Ok(());
+            }
+            let mut log = SecurityLog::load(SecurityLog::path_for(&cfg_path))?;
+            let password = prompt_password(&cfg)?;
+            let key = InMemoryKey::unlock(&cfg.identity, &cfg.keyring, password.expose())?;
+            let client = reqwest::Client::new();
+            let lt = match login(&cfg, &client, cfg.kdf.derive(password.expose())?).await {
+                Ok(lt) => lt,
+                Err(e) => {
+                    error!("Failed to login: {:?}", e);
+                    return Ok(());
+                }
+            };
+            let retry = cfg.pending_key_change.is_some();
+            let (new_ident, notice) = match cfg.pending_key_change.clone() {
+                Some(notice) => {
+                    // the last upload may have gone through with only the answer lost
+                    match get_self(&cfg, &client, &lt)
+                        .await
+                        .and_then(|me| CachedUser::new(me).map_err(GetUserError::InvalidKey))
+                    {
+                        Ok(me) if me.has_key(key.fingerprint()) => {
+                            cfg.pending_key_change = None;
+                            cfg.save(&cfg_path)?;
+                            info!("The server already has the key made by the last rotate-key");
+                            log.record(SecurityEvent::OwnKeyRotated {
+                                old: pubkey_fingerprint(&notice.old_pubkey)?,
+                                new: key.fingerprint().to_owned(),
+                            });
+                            return Ok(());
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            error!("Cannot check whether the server has the new key, try again: {:?}", e);
+                            return Ok(());
+                        }
+                    }
+                    info!("Uploading the key made by the last rotate-key again");
+                    (cfg.identity.clone(), notice)
+                }
+                None => {
+                    let new_ident = LocalIdentity::generate(KeyAlgorithm::default(), password.expose())?;
+                    let notice = key.sign_key_change(&new_ident)?;
+                    // save before uploading, losing the new key after the server switched would be unrecoverable
+                    let mut old_ident = std::mem::replace(&mut cfg.identity, new_ident.clone());
+                    old_ident.protect(password.expose())?;
+                    cfg.keyring.push(old_ident);
+                    cfg.pending_key_change = Some(notice.clone());
+                    cfg.save(&cfg_path)?;
+                    (new_ident, notice)
+                }
+            };
+            let old = pubkey_fingerprint(&notice.old_pubkey)?;
+            match upload_pubkey(&cfg, &client, &lt, &new_ident, notice).await {
+                Ok(()) => {
+                    cfg.pending_key_change = None;
+                    cfg.save(&cfg_path)?;
+                    info!("Rotated key, the previous one is kept to read older messages");
+                    log.record(SecurityEvent::OwnKeyRotated {
+                        old,
+                        new: pubkey_fingerprint(&Pubkey::from(new_ident.pubkey.clone()))?,
+                    });
+                }
+                Err(UploadPubkeyError::Rejected(status)) if retry => {
+                    // never drop the new key here, it is the only copy if the server has it after all
+                    error!("Server refused the new key: {}", status);
+                    error!("The new key is kept, run rotate-key again later, until then sending is disabled");
+                }
+                Err(UploadPubkeyError::Rejected(status)) => {
+                    // the server kept the previous key, so it is still the one to use
+                    error!("Server refused the new key: {}", status);
+                    if let Some(old_ident) = cfg.keyring.pop() {
+                        cfg.identity = old_ident;
+                    }
+                    cfg.pending_key_change = None;
+                    cfg.save(&cfg_path)?;
+                }
+                Err(UploadPubkeyError::RequestFailed) => {
+                    // the server may have switched before the connection broke, keep the new key
+                    error!("Failed to upload new key, it may or may not have reached the server");
+                    error!("Run rotate-key again to retry the upload, until then sending is disabled");
+                }
+            }
+        }
+        LaunchOptions::BackupIdentity { cfg_path, out } => {
+            let cfg = LocalServerEntry::load(&cfg_path)?;
+            if !cfg.identity.is_encrypted() {
+                error!("The private key in {:?} is not encrypted yet, log in once first", &cfg_path);
+                return Ok(());
+            }
+            // make sure the password the backed up keys are encrypted with is still known
+            let password = prompt_password(&cfg)?;
+            InMemoryKey::unlock(&cfg.identity, &cfg.keyring, password.expose())?;
+            let passphrase = prompt_secret("Backup passphrase: ")?;
+            if prompt_secret("Repeat backup passphrase: ")? != passphrase {
+                error!("Passphrases don't match");
+                return Ok(());
+            }
+            let text = IdentityBackup::of(&cfg).to_text(passphrase.expose())?;
+            match out {
+                Some(out) => {
+                    save_backup_text(&out, &text)?;
+                    info!("Written identity backup to {:?}", &out);
+                }
+                None => print!("{}", text),
+            }
+        }
+        LaunchOptions::RestoreIdentity { save_to, backup } => {
+            if save_to.exists() {
+                error!("{:?} already exists, refusing to overwrite it", &save_to);
+                return Ok(());
+            }
+            let text = match backup {
+                Some(path) => std::fs::read_to_string(path)?,
+                None => {
+                    info!("Paste the recovery text, then press Ctrl-D");
+                    let mut text = String::new();
+                    std::io::Read::read_to_string(&mut std::io::stdin(), &mut text)?;
+                    text
+                }
+            };
+            let backup = IdentityBackup::from_text(&text, prompt_secret("Backup passphrase: ")?.expose())?;
+            let mut cfg = backup.to_config();
+            let password = prompt_password(&cfg)?;
+            InMemoryKey::unlock(&cfg.identity, &cfg.keyring, password.expose())?;
+            let client = reqwest::Client::new();
+            let lt = match login(&cfg, &client, cfg.kdf.derive(password.expose())?).await {
+                Ok(lt) => lt,
+                Err(e) => {
+                    error!("Failed to login: {:?}", e);
+                    return Ok(());
+                }
+            };
+            match get_self(&cfg, &client, &lt)
+                .await
+                .and_then(|me| CachedUser::new(me).map_err(GetUserError::InvalidKey))
+            {
+                Ok(me) => match backup.check_published(&me) {
+                    Ok(()) => {
+                        cfg.uid = Some(me.uid());
+                        cfg.save(&save_to)?;
+                        SecurityLog::load(SecurityLog::path_for(&save_to))?.record(SecurityEvent::IdentityRestored {
+                            fingerprint: pubkey_fingerprint(&Pubkey::from(cfg.identity.pubkey.clone()))?,
+                        });
+                        info!("Restored identity of {} to {:?}", &cfg.email, &save_to);
+                    }
+                    Err(e) => error!("Not restoring: {}", e),
+                },
+                Err(e) => error!("Cannot look up the published key, not restoring: {:?}", e),
+            }
+        }
+        LaunchOptions::ApproveDevice { cfg_path } => {
+            let cfg = LocalServerEntry::load(&cfg_path)?;
+            if cfg.device.is_some() {
+                error!("Only the main device can link further devices");
+                return Ok(());
+            }
+            let password = prompt_password(&cfg)?;
+            let key = InMemoryKey::unlock(&cfg.identity, &cfg.keyring, password.expose())?;
+            let client = reqwest::Client::new();
+            let lt = match login(&cfg, &client, cfg.kdf.derive(password.expose())?).await {
+                Ok(lt) => lt,
+                Err(e) => {
+                    error!("Failed to login: {:?}", e);
+                    return Ok(());
+                }
+            };
+            let code = PairingCode::new(&key)?;
+            let offer = LinkOffer::new(&cfg);
+            if let Err(e) = post_pairing_offer(&cfg, &client, &lt, &code.seal_offer(&offer)?).await {
+                error!("Failed to start pairing: {:?}", e);
+                return Ok(());
+            }
+            if let Some(qr) = code.to_qr() {
+                println!("{}", qr);
+            }
+            println!("{}", code.to_text());
+            info!(
+                "Run `link-device` on the new device with {} and the code above, waiting {} minutes",
+                &cfg.http_addr,
+                PAIRING_VALID_MINUTES
+            );
+            let pending = loop {
+                if offer.is_expired() {
+                    error!("Pairing code expired");
+                    return Ok(());
+                }
+                tokio::time::delay_for(Duration::from_secs(2)).await;
+                match get_pending_devices(&cfg, &client, &lt).await {
+                    Ok(pending) => {
+                        if let Some(p) = pending.into_iter().find(|p| code.matches(p)) {
+                            break p;
+                        }
+                    }
+                    Err(e) => warn!("Cannot fetch pending devices: {:?}", e),
+                }
+            };
+            let fp = pubkey_fingerprint(&pending.pubkey)?;
+            info!("Device {:?} asks to be linked, key {}", &pending.name, fp);
+            print!("Link it? [y/N] ");
+            std::io::Write::flush(&mut std::io::stdout())?;
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer)?;
+            if answer.trim() != "y" {
+                info!("Not linked");
+                return Ok(());
+            }
+            let device = sign_device(&key, &pending)?;
+            match upload_device(&cfg, &client, &lt, &device).await {
+                Ok(()) => {
+                    info!("Linked {:?}, contacts will encrypt to it from their next login", &device.name);
+                    SecurityLog::load(SecurityLog::path_for(&cfg_path))?.record(SecurityEvent::DeviceLinked {
+                        name: device.name.clone(),
+                        fingerprint: fp,
+                    });
+                }
+                Err(e) => error!("Failed to upload device key: {:?}", e),
+            }
+        }
+        LaunchOptions::LinkDevice {
+            save_to,
+            name,
+            http_addr,
+            code,
+        } => {
+            if save_to.exists() {
+                error!("{:?} already exists, refusing to overwrite it", &save_to);
+                return Ok(());
+            }
+            let code = match code {
+                Some(code) => code,
+                None => {
+                    info!("Paste the pairing code shown by `approve-device`");
+                    let mut code = String::new();
+                    std::io::stdin().read_line(&mut code)?;
+                    code
+                }
+            };
+            let code = PairingCode::parse(&code)?;
+            let client = reqwest::Client::new();
+            let offer = match get_pairing_offer(&http_addr, &client, &code.offer_id()?).await {
+                Ok(sealed) => code.open_offer(&sealed)?,
+                Err(e) => {
+                    error!("No pairing with this code on {}, check both: {:?}", &http_addr, e);
+                    return Ok(());
+                }
+            };
+            if offer.is_expired() {
+                error!("Pairing code expired, run `approve-device` again");
+                return Ok(());
+            }
+            let password = prompt_secret(&format!("Password for {}: ", &offer.email))?;
+            let mut cfg = LocalServerEntry {
+                http_addr,
+                ws_addr: offer.ws_addr,
+                email: offer.email,
+                uid: None,
+                phash: None,
+                kdf: offer.kdf,
+                pending_kdf: None,
+                padding: PaddingPolicy::default(),
+                device: Some(name.clone()),
+                identity: LocalIdentity::generate(KeyAlgorithm::default(), password.expose())?,
+                keyring: Vec::new(),
+                pending_key_change: None,
+            };
+            let lt = match login(&cfg, &client, cfg.kdf.derive(password.expose())?).await {
+                Ok(lt) => lt,
+                Err(e) => {
+                    error!("Failed to login: {:?}", e);
+                    return Ok(());
+                }
+            };
+            match get_self(&cfg, &client, &lt).await {
+                Ok(me) if !pubkey_fingerprint(&me.pubkey).is_ok_and(|fp| code.is_primary(&fp)) => {
+                    error!("The server has a different main key for this account than the pairing code, not linking");
+                    return Ok(());
+                }
+                Ok(me) => cfg.uid = Some(me.uid),
+                Err(e) => {
+                    error!("Cannot look up the account: {:?}", e);
+                    return Ok(());
+                }
+            }
+            let request = code.request(&Pubkey::from(cfg.identity.pubkey.clone()), &name)?;
+            // save first, an approved key without its private half would be useless
+            cfg.save(&save_to)?;
+            match post_pending_device(&cfg, &client, &lt, &request).await {
+                Ok(()) => info!(
+                    "Written config to {:?}. Confirm key {} on the main device, then log in",
+                    &save_to,
+                    pubkey_fingerprint(&request.pubkey)?
+                ),
+                Err(e) => {
+                    error!("Failed to request linking: {:?}", e);
+                    std::fs::remove_file(&save_to)?;
+                }
+            }
         }
         LaunchOptions::Register {
             save_to,
             http_addr,
             email,
-            password,
         } => {
+            // prompted for, so it never shows up in shell history or the process list
+            let password = prompt_secret(&format!("Password for {}: ", &email))?;
+            if prompt_secret("Repeat password: ")? != password {
+                error!("Passwords don't match");
+                return Ok(());
+            }
             let client = reqwest::Client::new();
-            // generate password hash
-            let phash = hex::encode({
-                let mut hasher = Sha256::new();
-                hasher.update(password.as_bytes());
-                hasher.finalize().to_vec()
-            });
-            debug!("Register: derived phash {}", &phash);
-            let privkey = Rsa::generate(2048)?;
-            let privkey_serialized = String::from_utf8(privkey.private_key_to_pem().unwrap())?;
-            let pubkey_serialized = String::from_utf8(privkey.public_key_to_pem().unwrap())?;
-            let local_ident = LocalIdentity {
-                privkey: privkey_serialized.clone(),
-                pubkey: pubkey_serialized.clone(),
-            };
+            let kdf = PasswordKdf::generate()?;
+            let phash = kdf.derive(password.expose())?;
+            let local_ident = LocalIdentity::generate(KeyAlgorithm::default(), password.expose())?;
             match client
                 .post(&format!("{}{}", http_addr, "register"))
                 .body(
                     serde_json::to_string(&RegisterRequest {
                         email: email.to_owned(),
-                        password_hash: phash.to_owned(),
-                        pubkey: pubkey_serialized.to_owned(),
+                        password_hash: phash,
+                        pubkey: local_ident.pubkey.to_owned(),
                     })
                     .unwrap(),
                 )
@@ -101,13 +561,17 @@ async fn main() -> Result<(), Box<dyn Error>> {
                         http_addr: http_addr,
                         ws_addr: "".to_owned(),
                         email: email,
-                        phash: phash,
+                        uid: None,
+                        phash: None,
+                        kdf,
+                        pending_kdf: None,
+                        padding: PaddingPolicy::default(),
+                        device: None,
                         identity: local_ident,
+                        keyring: Vec::new(),
+                        pending_key_change: None,
                     };
-                    // sync code
-                    let f = File::create(&save_to)?;
-                    let buf = BufWriter::new(f);
-                    serde_json::to_writer_pretty(buf, &gen_cfg).unwrap();
+                    gen_cfg.save(&save_to)?;
                     info!(
                         "Written config to {:?}, make sure to edit the ws address!",
                         &save_to
@@ -128,92 +592,348 @@ pub enum LaunchOptions {
         #[structopt(parse(from_os_str))]
         cfg_path: PathBuf,
     },
+    /// Replace the identity key, keeping the old one to read older messages.
+    RotateKey {
+        #[structopt(parse(from_os_str))]
+        cfg_path: PathBuf,
+    },
+    /// Write a passphrase encrypted backup of the identity keys to `out`, or print it as recovery text.
+    BackupIdentity {
+        #[structopt(parse(from_os_str))]
+        cfg_path: PathBuf,
+        #[structopt(parse(from_os_str))]
+        out: Option<PathBuf>,
+    },
+    /// Recreate a config from a backup file, or from recovery text pasted on stdin.
+    /// Refused unless the server publishes the backed up key for the account.
+    RestoreIdentity {
+        #[structopt(parse(from_os_str))]
+        save_to: PathBuf,
+        #[structopt(parse(from_os_str))]
+        backup: Option<PathBuf>,
+    },
+    /// Show a pairing code for a new device and link it once it answers. Main device only.
+    ApproveDevice {
+        #[structopt(parse(from_os_str))]
+        cfg_path: PathBuf,
+    },
+    /// Generate a key for this device and ask to link it to the account of the pairing `code`
+    /// shown by `approve-device` for the server at `http_addr`, read from stdin if omitted.
+    LinkDevice {
+        #[structopt(parse(from_os_str))]
+        save_to: PathBuf,
+        name: String,
+        http_addr: String,
+        code: Option<String>,
+    },
     Register {
         #[structopt(parse(from_os_str))]
         save_to: PathBuf,
         http_addr: String,
         email: String,
-        password: String,
     },
 }
 
-async fn connect(cfg: LocalServerEntry) {
-    let client = reqwest::Client::new();
-    let lt = client
-        .post(&format!("{}{}", cfg.http_addr, "login"))
-        .body(
-            serde_json::to_string(&LoginRequest {
-                email: cfg.email.to_owned(),
-                password_hash: cfg.phash.to_owned(),
-            })
-            .unwrap(),
-        )
-        .send()
-        .await
-        .unwrap()
-        .json::<LoginToken>()
-        .await
-        .unwrap();
-    println!("{:?}", &lt);
+/// What `connect` keeps next to the config of the logged in account.
+struct AccountStores {
+    known: KnownKeys,
+    log: SecurityLog,
+    sessions: Sessions,
+    groups: GroupKeys,
+    replay: ReplayGuard,
+}
+
+async fn connect(cfg: LocalServerEntry, client: reqwest::Client, key: InMemoryKey, lt: LoginToken, stores: AccountStores) {
+    let AccountStores {
+        mut known,
+        mut log,
+        mut sessions,
+        mut groups,
+        mut replay,
+    } = stores;
 
     tokio::time::delay_for(Duration::from_millis(200)).await;
     let (mut wss, resp) = tokio_tungstenite::connect_async(
         http::request::Request::builder()
             .uri(&cfg.ws_addr)
-            .header("Authorization", &lt.tk)
+            .header("Authorization", lt.tk.as_str())
             .body(())
             .unwrap(),
     )
     .await
     .unwrap();
     info!("connected to {}", &cfg.ws_addr);
+    let ours = ProtocolInfo::client();
+    let mut server: Option<ProtocolInfo> = None;
+    let mut handshake_missing = false;
+    if let Err(e) = wss.send(WsServerboundPayload::Hello(ours.clone()).into()).await {
+        error!("Failed to send handshake: {}", e);
+    }
     let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
     let mut run = true;
-    let mut dm_dest = None;
+    let mut dest: Option<MessageActor> = None;
     let mut state = ClientState::Connected;
-    let mut cache_users: HashMap<UserId, PublicUserRecord> = HashMap::new();
-    let key = InMemoryKey::try_from(cfg.identity.clone()).unwrap();
+    let mut cache_users: HashMap<UserId, CachedUser> = HashMap::new();
+    let mut outbox = Outbox::default();
+    let mut seen_umids: HashSet<UserMessageId> = HashSet::new();
+    // sender keys asked for, once per connection so a member who can't answer isn't flooded
+    let mut asked_keys: HashSet<(GroupId, UserId)> = HashSet::new();
+    // by query id, the user a `/history` was for or `None` for the unseen messages
+    let mut history_requests: HashMap<u64, Option<UserId>> = HashMap::new();
+    let mut next_history_id = 0;
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
     while run {
         tokio::select! {
             maybe_ws = wss.next() => {
                 match maybe_ws {
                     Some(raw_ws_inc) => {
                         debug!("ws raw inc {:?}", &raw_ws_inc);
-                        if let Ok(tungstenite::Message::Text(m_ws_clientbound)) = raw_ws_inc {
-                            if let Ok(wsc) = serde_json::from_str::<WsClientboundPayload>(&m_ws_clientbound) {
-                                debug!("ws inc decoded: {:?}", &wsc);
-                                match wsc {
-                                    WsClientboundPayload::NewMessage(m) => {
-                                        let uid = &m.from;
-                                        // try fetch user data
-                                        if let Some(pur) = cache_users.get(uid) {
-                                            if let Some(dec) = pur.decrypt(m.content) {
-                                                info!("(decrypted, {}) <<< {}", m.from, dec);
-                                            } else {
-                                                error!("Failed to decrypt incoming message");
+                        match raw_ws_inc {
+                            Ok(tungstenite::Message::Text(m_ws_clientbound)) => {
+                                if let Ok(wsc) = serde_json::from_str::<WsClientboundPayload>(&m_ws_clientbound) {
+                                    debug!("ws inc decoded: {:?}", &wsc);
+                                    if server.is_none() && !handshake_missing && !matches!(wsc, WsClientboundPayload::Welcome(_)) {
+                                        warn!("Server did not answer the handshake, it may be older than this client");
+                                        handshake_missing = true;
+                                    }
+                                    let mut incoming = Vec::new();
+                                    let mut key_requests: Vec<(UserId, GroupId)> = Vec::new();
+                                    let mut controls_out: Vec<(UserId, DmControl)> = Vec::new();
+                                    match wsc {
+                                        WsClientboundPayload::Welcome(theirs) => match ours.negotiate(&theirs) {
+                                            Ok(version) => {
+                                                info!("Server speaks protocol {}, using {}", theirs.version, version);
+                                                if theirs.has(CAP_HISTORY) {
+                                                    // whatever arrived while we were offline
+                                                    let id = next_history_id;
+                                                    next_history_id += 1;
+                                                    match wss.send(WsServerboundPayload::History { id, query: HistoryQuery::Unseen }.into()).await {
+                                                        Ok(_) => {
+                                                            history_requests.insert(id, None);
+                                                        },
+                                                        Err(e) => error!("Failed to ask for unseen messages: {}", e),
+                                                    }
+                                                }
+                                                server = Some(theirs);
+                                            },
+                                            Err(e) => {
+                                                error!("Cannot talk to this server: {}", e);
+                                                run = false;
                                             }
-                                        } else {
-                                            match get_user(&cfg, &client, uid).await {
+                                        },
+                                        WsClientboundPayload::NewMessage(m) => incoming.push(m),
+                                        WsClientboundPayload::NewMessages(batch) => {
+                                            info!("Got {} messages from the server", batch.len());
+                                            incoming.extend(batch);
+                                        },
+                                        WsClientboundPayload::History { id, messages } => match history_requests.remove(&id) {
+                                            Some(Some(uid)) => {
+                                                let me = cfg.uid.unwrap();
+                                                let fetched = match fetch_user(&cfg, &client, &mut known, &mut log, &mut cache_users, me).await {
+                                                    Ok(_) => fetch_user(&cfg, &client, &mut known, &mut log, &mut cache_users, uid).await.map(|_| ()),
+                                                    Err(e) => Err(e),
+                                                };
+                                                match fetched {
+                                                    Ok(()) => show_history(&cfg, &key, &known, &sessions, &cache_users, uid, messages),
+                                                    Err(e) => error!("Cannot show history with {}: {:?}", uid, e),
+                                                }
+                                            },
+                                            Some(None) => {
+                                                info!("Got {} messages sent while offline", messages.len());
+                                                incoming.extend(messages);
+                                            },
+                                            None => {
+                                                warn!("Ignored history {} that wasn't asked for", id);
+                                            }
+                                        },
+                                        WsClientboundPayload::MessageSent(umid) => match outbox.acked(AckKind::Dm) {
+                                            Some((id, to)) => info!("(sent {} to {} as {})", id, to, umid),
+                                            None => warn!("Server acknowledged message {} that wasn't sent", umid),
+                                        },
+                                        WsClientboundPayload::PresenceChanged { uid, online } => {
+                                            // only contacts we talked to, the server may tell about anyone visible
+                                            if let Some(pur) = cache_users.get_mut(&uid) {
+                                                if pur.record.online != online {
+                                                    pur.record.online = online;
+                                                    info!("(presence) {} is {}", sender_label(&cfg, &known, uid), presence_label(online));
+                                                }
+                                            }
+                                        },
+                                        WsClientboundPayload::GroupMessageSent(gmid) => match outbox.acked(AckKind::Group) {
+                                            Some((id, to)) => info!("(sent {} to {} as {})", id, to, gmid),
+                                            None => warn!("Server acknowledged group message {} that wasn't sent", gmid),
+                                        },
+                                        // our own message coming back, from this device or a linked one
+                                        WsClientboundPayload::NewGroupMessage(m) if cfg.uid == Some(m.from) => {},
+                                        WsClientboundPayload::NewGroupMessage(m) => {
+                                            let uid = m.from;
+                                            match fetch_user(&cfg, &client, &mut known, &mut log, &mut cache_users, uid).await {
                                                 Ok(pur) => {
-                                                    cache_users.insert(uid.to_owned(), pur.clone());
-                                                    info!("Added user cache {}", uid);
-                                                    if let Some(dec) = pur.decrypt(m.content) {
-                                                        info!("(decrypted, {}) <<< {}", m.from, dec);
-                                                    } else {
-                                                        error!("Failed to decrypt incoming message");
+                                                    match groups.decrypt(pur, m.to, m.content) {
+                                                        Ok(dec) => match replay.open(&cfg.http_addr, uid, MessageActor::Group(m.to), m.time_posted, &dec) {
+                                                            Ok(delivery) => info!(
+                                                                "(decrypted, group {}, {}) <<< {}",
+                                                                m.to,
+                                                                delivery_label(&cfg, &known, uid, &delivery),
+                                                                delivery.body()
+                                                            ),
+                                                            Err(ReplayError::Duplicate(counter)) => {
+                                                                debug!("Skipped copy of group {} message {} from {}", m.to, counter, uid);
+                                                            },
+                                                            Err(e) => {
+                                                                error!("Dropped group {} message from {}: {}", m.to, uid, e);
+                                                                log.record(SecurityEvent::ReplayDetected {
+                                                                    from: uid,
+                                                                    to: MessageActor::Group(m.to),
+                                                                    reason: e.to_string(),
+                                                                });
+                                                            }
+                                                        },
+                                                        // written again by the sender because the ack was late
+                                                        Err(CryptoError::AlreadyRead) => {
+                                                            debug!("Skipped copy of a group {} message from {} read before", m.to, uid);
+                                                        },
+                                                        Err(CryptoError::NoSession) if asked_keys.insert((m.to, uid)) => {
+                                                            warn!("No group {} key of {} yet, asking them for it", m.to, uid);
+                                                            controls_out.push((uid, DmControl::SenderKeyRequest(m.to)));
+                                                        }
+                                                        Err(e) => {
+                                                            error!("Failed to decrypt group {} message from {}: {}", m.to, uid, e);
+                                                            log.record(SecurityEvent::DecryptFailed {
+                                                                from: uid,
+                                                                to: MessageActor::Group(m.to),
+                                                                reason: e.to_string(),
+                                                            });
+                                                        }
+                                                    }
+                                                    if let Err(e) = groups.save() {
+                                                        error!("Failed to save group keys: {}", e);
+                                                    }
+                                                    if let Err(e) = replay.save() {
+                                                        error!("Failed to save replay counters: {}", e);
                                                     }
                                                 },
                                                 Err(e) => {
                                                     error!("Failed to get user {}: {:?}", uid, e);
                                                 }
                                             }
+                                        },
+                                    }
+                                    // batches may overlap with what was already shown, e.g. history sent after live messages
+                                    incoming.sort_by_key(|m| m.time_posted);
+                                    for m in incoming.drain(..) {
+                                        if !seen_umids.insert(m.umid.clone()) {
+                                            debug!("Skipped message {} seen before", m.umid);
+                                            continue;
                                         }
-                                    },
-                                    _ => {}
+                                        let uid = m.from;
+                                        // try fetch user data
+                                        match fetch_user(&cfg, &client, &mut known, &mut log, &mut cache_users, uid).await {
+                                            Ok(pur) => {
+                                                match sessions.decrypt(&key, pur, m.content) {
+                                                    Ok(_) if cfg.uid.is_some() && cfg.uid != Some(m.to) => {
+                                                        error!("Dropped message from {}: delivered to us but addressed to {}", uid, m.to);
+                                                        log.record(SecurityEvent::ReplayDetected {
+                                                            from: uid,
+                                                            to: MessageActor::Dm(m.to),
+                                                            reason: "delivered to us but addressed to someone else".to_owned(),
+                                                        });
+                                                    },
+                                                    Ok(dec) => match replay.open(&cfg.http_addr, uid, MessageActor::Dm(m.to), m.time_posted, &dec) {
+                                                        Ok(delivery) => show_dm(&cfg, &known, &mut groups, &mut key_requests, uid, delivery),
+                                                        // written again by the sender because the ack was late
+                                                        Err(ReplayError::Duplicate(counter)) => {
+                                                            debug!("Skipped copy of message {} from {}", counter, uid);
+                                                        },
+                                                        Err(e) => {
+                                                            error!("Dropped message from {}: {}", uid, e);
+                                                            log.record(SecurityEvent::ReplayDetected {
+                                                                from: uid,
+                                                                to: MessageActor::Dm(m.to),
+                                                                reason: e.to_string(),
+                                                            });
+                                                        }
+                                                    },
+                                                    Err(CryptoError::AlreadyRead) => {
+                                                        debug!("Skipped copy of a message from {} read before", uid);
+                                                    },
+                                                    Err(e) => {
+                                                        error!("Failed to decrypt incoming message from {}: {}", uid, e);
+                                                        log.record(SecurityEvent::DecryptFailed {
+                                                            from: uid,
+                                                            to: MessageActor::Dm(m.to),
+                                                            reason: e.to_string(),
+                                                        });
+                                                    }
+                                                }
+                                                if let Err(e) = sessions.save() {
+                                                    error!("Failed to save sessions: {}", e);
+                                                }
+                                                if let Err(e) = replay.save() {
+                                                    error!("Failed to save replay counters: {}", e);
+                                                }
+                                            },
+                                            Err(e) => {
+                                                error!("Failed to get user {}: {:?}", uid, e);
+                                            }
+                                        }
+                                    }
+                                    for (uid, gid) in key_requests {
+                                        // the server could ask on behalf of anyone, only hand the key to current members
+                                        let member = match get_group(&cfg, &client, &lt, gid).await {
+                                            Ok(group) => group.members.contains(&uid),
+                                            Err(e) => {
+                                                error!("Failed to get group {}: {:?}", gid, e);
+                                                false
+                                            }
+                                        };
+                                        match groups.distribution(gid) {
+                                            Some(dist) if member => controls_out.push((uid, DmControl::SenderKey(dist))),
+                                            Some(_) => warn!("Ignored request for group {} key from {}, not a member", gid, uid),
+                                            None => debug!("No group {} key to send to {} yet", gid, uid),
+                                        }
+                                    }
+                                    for (uid, ctl) in controls_out {
+                                        match cache_users.get(&uid) {
+                                            Some(_) if cfg.uid.is_none() => {
+                                                error!("Not sent to {}: own user id is unknown, log in again", uid);
+                                            },
+                                            Some(pur) if known.is_trusted(&cfg.http_addr, uid) => {
+                                                match encrypt_dm(&cfg, &client, &key, &mut sessions, &mut replay, pur, PayloadKind::Control, &ctl.to_text()).await {
+                                                    Ok(enc) => {
+                                                        let (id, payload) = outbox.push(MessageActor::Dm(uid), WsServerboundPayload::NewUserMessage {
+                                                            to: uid,
+                                                            content: enc
+                                                        });
+                                                        debug!("Group key message {} to {}", id, uid);
+                                                        if let Err(e) = wss.send(payload.into()).await {
+                                                            outbox.send_failed(id);
+                                                            warn!("Message {} not sent yet, will retry: {}", id, e);
+                                                        }
+                                                    },
+                                                    Err(e) => {
+                                                        error!("Failed to encrypt group key message for {}: {}", uid, e);
+                                                    }
+                                                }
+                                            },
+                                            _ => {
+                                                warn!("Group key message not sent to {}: their key changed, check it and run /accept {}", uid, uid);
+                                            }
+                                        }
+                                    }
+                                } else {
+                                    warn!(
+                                        "Ignored {} from the server, it may be newer than this client",
+                                        payload_kind(&m_ws_clientbound).unwrap_or_else(|| "malformed payload".to_owned())
+                                    );
                                 }
+                            },
+                            Ok(tungstenite::Message::Close(Some(frame))) => {
+                                error!("Server closed the connection: {}", frame.reason);
+                            },
+                            _ => {
+                                warn!("ws unknown inc");
                             }
-                        } else {
-                            warn!("ws unknown inc");
                         }
                     },
                     None => {
@@ -222,92 +942,1006 @@ async fn connect(cfg: LocalServerEntry) {
                     }
                 }
             }
+            _ = ticker.tick() => {
+                let due = outbox.due();
+                for (id, payload) in due.resend {
+                    info!("Sending message {} again", id);
+                    if let Err(e) = wss.send(payload.into()).await {
+                        outbox.send_failed(id);
+                        warn!("Message {} not sent yet, will retry: {}", id, e);
+                    }
+                }
+                for (id, to, reason) in due.failed {
+                    error!("Message {} to {} failed: {}", id, to, reason);
+                }
+            }
             Some(Ok(ln)) = lines.next() => {
                 debug!("> {}", &ln);
                 match parse(&ln, state.clone()) {
                     Ok(cmd) => match cmd {
-                        CliCommand::SelectGroup(gid) => {},
+                        CliCommand::SelectGroup(_) if !server.as_ref().is_some_and(|s| s.has(CAP_GROUPS)) => {
+                            error!("This server doesn't support groups");
+                        },
+                        CliCommand::SelectGroup(gid) => {
+                            match get_group(&cfg, &client, &lt, gid).await {
+                                Ok(group) if cfg.uid.is_some_and(|me| group.members.contains(&me)) => {
+                                    info!("(group) Targeting group {}, {} members", gid, group.members.len());
+                                    if let Some(motd) = &group.motd {
+                                        info!("(group {}) {}", gid, motd);
+                                    }
+                                    dest = Some(MessageActor::Group(gid));
+                                },
+                                Ok(_) => {
+                                    error!("Not a member of group {}", gid);
+                                },
+                                Err(e) => {
+                                    error!("Failed to get group {}: {:?}", gid, e);
+                                }
+                            }
+                        },
                         CliCommand::SelectUser(uid) => {
-                            if let Some(pur) = cache_users.get(&uid) {
-                                // user exists and is cached
-
-                            info!("(dm) Targeting {}", &uid);
-                                dm_dest = Some(uid);
-                            } else {
-                                // user isn't cached, ask server
-                                match get_user(&cfg, &client, &uid).await {
-                                    Ok(pur) => {
-                                        cache_users.insert(uid, pur);
-                                        info!("(dm) Fetched data, targeting {}", &uid);
-                                        dm_dest = Some(uid);
+                            match fetch_user(&cfg, &client, &mut known, &mut log, &mut cache_users, uid).await {
+                                Ok(pur) => {
+                                    info!("(dm) Targeting {}, {}", sender_label(&cfg, &known, uid), presence_label(pur.record.online));
+                                    dest = Some(MessageActor::Dm(uid));
+                                },
+                                Err(e) => {
+                                    error!("Failed to get user {}: {:?}", uid, e);
+                                }
+                            }
+                        },
+                        CliCommand::Text(s) => match dest {
+                            Some(MessageActor::Group(_)) if !server.as_ref().is_some_and(|s| s.has(CAP_GROUPS)) => {
+                                error!("Not sent: this server doesn't support groups");
+                            },
+                            Some(MessageActor::Group(gid)) => match (cfg.uid, get_group(&cfg, &client, &lt, gid).await) {
+                                (None, _) => {
+                                    error!("Not sent: own user id is unknown, log in again");
+                                },
+                                (_, Err(e)) => {
+                                    error!("Not sent: failed to get group {}: {:?}", gid, e);
+                                },
+                                (Some(me), Ok(group)) if !group.members.contains(&me) => {
+                                    error!("Not sent: no longer a member of group {}", gid);
+                                },
+                                (Some(me), Ok(group)) => {
+                                    match groups.prepare(gid, &group.members) {
+                                        Ok(Some(dist)) => {
+                                            // the other members need the new sender key before anything encrypted with it
+                                            let ctl = DmControl::SenderKey(dist).to_text();
+                                            for uid in group.members.iter().copied().filter(|uid| *uid != me) {
+                                                match fetch_user(&cfg, &client, &mut known, &mut log, &mut cache_users, uid).await {
+                                                    Ok(_) if !known.is_trusted(&cfg.http_addr, uid) => {
+                                                        error!("Group {} key not sent to {}: their key changed, check it and run /accept {}", gid, uid, uid);
+                                                    },
+                                                    Ok(pur) => match encrypt_dm(&cfg, &client, &key, &mut sessions, &mut replay, pur, PayloadKind::Control, &ctl).await {
+                                                        Ok(enc) => {
+                                                            let (id, payload) = outbox.push(MessageActor::Dm(uid), WsServerboundPayload::NewUserMessage {
+                                                                to: uid,
+                                                                content: enc
+                                                            });
+                                                            info!("(group {} key for {}, {})", gid, uid, id);
+                                                            if let Err(e) = wss.send(payload.into()).await {
+                                                                outbox.send_failed(id);
+                                                                warn!("Message {} not sent yet, will retry: {}", id, e);
+                                                            }
+                                                        },
+                                                        Err(e) => {
+                                                            error!("Failed to encrypt group {} key for {}: {}", gid, uid, e);
+                                                        }
+                                                    },
+                                                    Err(e) => {
+                                                        error!("Group {} key not sent to {}: failed to get user: {:?}", gid, uid, e);
+                                                    }
+                                                }
+                                            }
+                                        },
+                                        Ok(None) => {},
+                                        Err(e) => {
+                                            error!("Failed to prepare group {} key: {}", gid, e);
+                                        }
+                                    }
+                                    let sealed = replay.seal(&cfg.http_addr, me, MessageActor::Group(gid), PayloadKind::Text, &s);
+                                    let enc = groups.encrypt(&key, gid, &sealed);
+                                    if let Err(e) = groups.save() {
+                                        error!("Failed to save group keys: {}", e);
+                                    }
+                                    if let Err(e) = replay.save() {
+                                        error!("Failed to save replay counters: {}", e);
+                                    }
+                                    match enc {
+                                        Ok(enc) => {
+                                            let (id, payload) = outbox.push(MessageActor::Group(gid), WsServerboundPayload::NewGroupMessage {
+                                                to: gid,
+                                                content: enc
+                                            });
+                                            info!("(encrypted, group {}, self, {}) >>> {}", gid, id, s);
+                                            if let Err(e) = wss.send(payload.into()).await {
+                                                outbox.send_failed(id);
+                                                warn!("Message {} not sent yet, will retry: {}", id, e);
+                                            }
+                                        },
+                                        Err(e) => {
+                                            error!("Failed to encrypt group {} message: {}", gid, e);
+                                        }
+                                    }
+                                },
+                            },
+                            Some(MessageActor::Dm(uid)) => match cache_users.get(&uid) {
+                                Some(pur) if !known.is_trusted(&cfg.http_addr, pur.uid()) => {
+                                    error!("Not sent: key of {} changed, check it and run /accept {}", pur.uid(), pur.uid());
+                                },
+                                Some(_) if cfg.uid.is_none() => {
+                                    error!("Not sent: own user id is unknown, log in again");
+                                },
+                                Some(pur) => match encrypt_dm(&cfg, &client, &key, &mut sessions, &mut replay, pur, PayloadKind::Text, &s).await {
+                                    Ok(enc) => {
+                                        let (id, payload) = outbox.push(MessageActor::Dm(pur.uid()), WsServerboundPayload::NewUserMessage {
+                                            to: pur.uid(),
+                                            content: enc
+                                        });
+                                        info!("(encrypted, self, {}) >>> {}", id, s);
+                                        if let Err(e) = wss.send(payload.into()).await {
+                                            outbox.send_failed(id);
+                                            warn!("Message {} not sent yet, will retry: {}", id, e);
+                                        }
                                     },
                                     Err(e) => {
-                                        error!("Failed to get user {}: {:?}", uid, e);
+                                        error!("Failed to encrypt message: {}", e);
                                     }
+                                },
+                                None => {
+                                    warn!("Missing recipient");
                                 }
+                            },
+                            None => {
+                                warn!("Missing recipient");
                             }
                         },
-                        CliCommand::Text(s) => {
-                            match dm_dest {
-                                Some(uid) => {
-                                    if let Some(enc) = key.encrypt(&s) {
-                                        wss.send(WsServerboundPayload::NewUserMessage {
-                                            to: uid,
-                                            content: ClientMessage::from(enc)
-                                        }.into()).await;
-                                        info!("(encrypted, self) >>> {}", s);
-                                    } else {
-                                        error!("Failed to encrypt message");
+                        CliCommand::AcceptKey(uid) => {
+                            match known.accept(&cfg.http_addr, uid) {
+                                Some(fp) => {
+                                    warn!("Accepted new key of {} ({})", uid, fp);
+                                    log.record(SecurityEvent::KeyAccepted { uid, fingerprint: fp });
+                                    if let Err(e) = known.save() {
+                                        error!("Failed to save known keys: {}", e);
                                     }
                                 },
                                 None => {
-                                    warn!("Missing recipient");
+                                    warn!("No changed key waiting to be accepted for {}", uid);
                                 }
                             }
-                        }
-                        _ => {
-                            warn!("Command not recognized/implemented yet");
-                        }
-                    },
-                    Err(e) => {
-                        print_parse_e(e);
-                    }
-                }
-            }
-            /*Some(_) = ctrlc_r.next() => {
-                info!("shutting down");
-                run = false;
-            }*/
-        }
-    }
-}
-
-#[derive(Debug)]
-pub enum GetUserError {
-    RequestFailed,
-    DeserializeFailed,
-}
-
-async fn get_user(
-    cfg: &LocalServerEntry,
-    client: &reqwest::Client,
-    uid: &UserId,
-) -> Result<PublicUserRecord, GetUserError> {
-    let resp = client
-        .get(&format!("{}{}/{}", cfg.http_addr, "users", uid))
-        .send()
-        .await
-        .map_err(|_| GetUserError::RequestFailed)?;
-    resp.json()
-        .map_err(|_| GetUserError::DeserializeFailed)
-        .await
-}
-
-fn print_parse_e(e: CliParseError) {
-    match e {
-        CliParseError::CannotChatNow(_) => {
-            error!("Cannot chat right now");
-        }
+                        },
+                        CliCommand::Verify { uid, confirm } => {
+                            match fetch_user(&cfg, &client, &mut known, &mut log, &mut cache_users, uid).await {
+                                Ok(pur) => {
+                                    let own = Pubkey::from(cfg.identity.pubkey.clone());
+                                    match safety_number(&own, &pur.record.pubkey) {
+                                        Ok(sn) if !confirm => {
+                                            info!("Safety number with {}, compare it with them out of band:\n{}", uid, sn);
+                                            info!("If it matches, run /verify {} confirm", uid);
+                                        },
+                                        Ok(_) => {
+                                            if known.verify(&cfg.http_addr, uid) {
+                                                info!("Marked {} as verified", sender_label(&cfg, &known, uid));
+                                                log.record(SecurityEvent::Verified { uid });
+                                                if let Err(e) = known.save() {
+                                                    error!("Failed to save known keys: {}", e);
+                                                }
+                                            } else {
+                                                error!("Key of {} changed, /accept it before verifying", uid);
+                                            }
+                                        },
+                                        Err(e) => {
+                                            error!("Cannot compute safety number for {}: {}", uid, e);
+                                        }
+                                    }
+                                },
+                                Err(e) => {
+                                    error!("Failed to get user {}: {:?}", uid, e);
+                                }
+                            }
+                        },
+                        CliCommand::History(query) => {
+                            match (dest, cfg.uid) {
+                                (None, _) | (Some(MessageActor::Group(_)), _) => warn!("Missing recipient, target a user with /u first"),
+                                (_, None) => error!("Own user id is unknown, log in again"),
+                                (Some(_), _) if !server.as_ref().is_some_and(|s| s.has(CAP_HISTORY)) => {
+                                    error!("This server doesn't keep history");
+                                },
+                                (Some(MessageActor::Dm(uid)), Some(_)) => {
+                                    let id = next_history_id;
+                                    next_history_id += 1;
+                                    match wss.send(WsServerboundPayload::History { id, query }.into()).await {
+                                        Ok(_) => {
+                                            history_requests.insert(id, Some(uid));
+                                        },
+                                        Err(e) => error!("Failed to ask for history: {}", e),
+                                    }
+                                },
+                            }
+                        },
+                        CliCommand::ListGroups => {
+                            match get_self(&cfg, &client, &lt).await {
+                                Ok(own) => {
+                                    let gids = own.groups.unwrap_or_default();
+                                    if gids.is_empty() {
+                                        info!("(groups) Not a member of any group, /group create makes one");
+                                    }
+                                    for gid in gids {
+                                        match get_group(&cfg, &client, &lt, gid).await {
+                                            Ok(group) => info!(
+                                                "(groups) {}, {} members{}",
+                                                gid,
+                                                group.members.len(),
+                                                group.motd.map(|m| format!(": {}", m)).unwrap_or_default()
+                                            ),
+                                            Err(e) => info!("(groups) {} (failed to get details: {:?})", gid, e),
+                                        }
+                                    }
+                                },
+                                Err(e) => {
+                                    error!("Failed to get own profile: {:?}", e);
+                                }
+                            }
+                        },
+                        CliCommand::CreateGroup { motd } => {
+                            match create_group(&cfg, &client, &lt, &CreateGroupRequest { motd }).await {
+                                Ok(group) => {
+                                    info!("Created group {}, /g {} to target it, /group {} invite <user-id> to add members", group.gid, group.gid, group.gid);
+                                },
+                                Err(e) => {
+                                    error!("Failed to create group: {:?}", e);
+                                }
+                            }
+                        },
+                        CliCommand::InviteToGroup { gid, uid } => {
+                            // pins their key, and nobody gets invited by a typo
+                            match fetch_user(&cfg, &client, &mut known, &mut log, &mut cache_users, uid).await {
+                                Ok(_) => match invite_to_group(&cfg, &client, &lt, gid, &GroupInviteRequest { uid }).await {
+                                    Ok(()) => info!("Added {} to group {}, they get the group key with the next message", sender_label(&cfg, &known, uid), gid),
+                                    Err(e) => error!("Failed to add {} to group {}: {:?}", uid, gid, e),
+                                },
+                                Err(e) => {
+                                    error!("Failed to get user {}: {:?}", uid, e);
+                                }
+                            }
+                        },
+                        CliCommand::LeaveGroup(gid) => match cfg.uid {
+                            Some(me) => match remove_from_group(&cfg, &client, &lt, gid, me).await {
+                                Ok(()) => {
+                                    info!("Left group {}", gid);
+                                    groups.forget(gid);
+                                    if let Err(e) = groups.save() {
+                                        error!("Failed to save group keys: {}", e);
+                                    }
+                                    if dest == Some(MessageActor::Group(gid)) {
+                                        dest = None;
+                                    }
+                                },
+                                Err(e) => {
+                                    error!("Failed to leave group {}: {:?}", gid, e);
+                                }
+                            },
+                            None => {
+                                error!("Own user id is unknown, log in again");
+                            }
+                        },
+                        CliCommand::ListMembers(gid) => {
+                            match get_group(&cfg, &client, &lt, gid).await {
+                                Ok(group) => {
+                                    info!("(group {}) {} members", gid, group.members.len());
+                                    for uid in group.members {
+                                        info!("(group {}) {}", gid, sender_label(&cfg, &known, uid));
+                                    }
+                                },
+                                Err(e) => {
+                                    error!("Failed to get group {}: {:?}", gid, e);
+                                }
+                            }
+                        },
+                        CliCommand::SetMotd { gid, motd } => {
+                            match set_group_motd(&cfg, &client, &lt, gid, &SetMotdRequest { motd }).await {
+                                Ok(()) => info!("Updated message of the day of group {}", gid),
+                                Err(e) => error!("Failed to update group {}: {:?}", gid, e),
+                            }
+                        },
+                        CliCommand::SetStatus(status) => {
+                            let request = PresenceUpdateRequest { status: Some(status.clone()), visibility: None };
+                            match update_presence(&cfg, &client, &lt, &request).await {
+                                Ok(()) => info!("Status set to {:?}", status),
+                                Err(e) => error!("Failed to set status: {:?}", e),
+                            }
+                        },
+                        CliCommand::SetVisibility(visibility) => {
+                            let request = PresenceUpdateRequest { status: None, visibility: Some(visibility.clone()) };
+                            match update_presence(&cfg, &client, &lt, &request).await {
+                                Ok(()) => info!("Visibility set to {:?}", visibility),
+                                Err(e) => error!("Failed to set visibility: {:?}", e),
+                            }
+                        },
+                        CliCommand::ShowSecurityLog(count) => {
+                            for r in log.recent(count) {
+                                info!("(security) {} {}", r.time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S"), r.event);
+                            }
+                        },
+                        _ => {
+                            warn!("Command not recognized/implemented yet");
+                        }
+                    },
+                    Err(e) => {
+                        print_parse_e(e);
+                    }
+                }
+            }
+            /*Some(_) = ctrlc_r.next() => {
+                info!("shutting down");
+                run = false;
+            }*/
+        }
+    }
+}
+
+/// Pins the key of `pur` on first sight and warns loudly if it differs from the pinned one.
+fn check_pin(cfg: &LocalServerEntry, known: &mut KnownKeys, log: &mut SecurityLog, pur: &PublicUserRecord) {
+    match known.check(&cfg.http_addr, pur.uid, &pur.pubkey) {
+        Ok(PinStatus::Match) => {
+            return;
+        }
+        Ok(PinStatus::New(fp)) => {
+            info!("Pinned key of {} ({})", pur.uid, fp);
+            log.record(SecurityEvent::KeyPinned {
+                uid: pur.uid,
+                fingerprint: fp,
+            });
+        }
+        Ok(PinStatus::Changed { pinned, offered })
+            if vouches_for(pur.key_change.as_ref(), &pinned, &offered) =>
+        {
+            warn!("*** Key of {} changed, signed off by their previous key ***", pur.uid);
+            warn!("*** previous: {}", pinned);
+            warn!("*** new:      {}", offered);
+            known.accept(&cfg.http_addr, pur.uid);
+            warn!("*** Run /verify {} again to check the new key", pur.uid);
+            log.record(SecurityEvent::KeyChanged {
+                uid: pur.uid,
+                pinned,
+                offered,
+                vouched: true,
+            });
+        }
+        Ok(PinStatus::Changed { pinned, offered }) => {
+            error!("!!! KEY OF USER {} HAS CHANGED !!!", pur.uid);
+            error!("!!! pinned:  {}", pinned);
+            error!("!!! offered: {}", offered);
+            error!(
+                "!!! The server may be impersonating them. Sending to {} is blocked until you run /accept {}",
+                pur.uid, pur.uid
+            );
+            log.record(SecurityEvent::KeyChanged {
+                uid: pur.uid,
+                pinned,
+                offered,
+                vouched: false,
+            });
+        }
+        Err(e) => {
+            error!("Cannot pin key of {}: {}", pur.uid, e);
+            return;
+        }
+    }
+    if let Err(e) = known.save() {
+        error!("Failed to save known keys: {}", e);
+    }
+}
+
+/// Shows a DM that passed the replay checks, or handles it if it is a `DmControl`.
+fn show_dm(
+    cfg: &LocalServerEntry,
+    known: &KnownKeys,
+    groups: &mut GroupKeys,
+    key_requests: &mut Vec<(UserId, GroupId)>,
+    uid: UserId,
+    delivery: Delivery,
+) {
+    match delivery.kind() {
+        PayloadKind::Control => match DmControl::parse(delivery.body()) {
+            Some(ctl) => handle_dm_control(cfg, known, groups, key_requests, uid, ctl),
+            None => error!("Ignored unknown control message from {}", uid),
+        },
+        PayloadKind::Text => {
+            info!("(decrypted, {}) <<< {}", delivery_label(cfg, known, uid, &delivery), delivery.body())
+        }
+    }
+}
+
+/// Shows the messages in `batch` between us and `uid` as one block, oldest first.
+/// Nothing is recorded: they may have been shown before, and control messages aren't acted on again.
+fn show_history(
+    cfg: &LocalServerEntry,
+    key: &InMemoryKey,
+    known: &KnownKeys,
+    sessions: &Sessions,
+    cache_users: &HashMap<UserId, CachedUser>,
+    uid: UserId,
+    mut batch: Vec<PublicUserMessage>,
+) {
+    let me = cfg.uid.unwrap();
+    batch.retain(|m| (m.from == uid && m.to == me) || (m.from == me && m.to == uid));
+    batch.sort_by_key(|m| m.time_posted);
+    let mut shown = HashSet::new();
+    batch.retain(|m| shown.insert(m.umid.clone()));
+    info!("----- history with {}, {} messages -----", sender_label(cfg, known, uid), batch.len());
+    for m in batch {
+        let when = m.time_posted.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M");
+        let who = if m.from == me { "self".to_owned() } else { sender_label(cfg, known, uid) };
+        match sessions.reread(key, &cache_users[&m.from], m.content) {
+            Ok(dec) => match ReplayGuard::reopen(m.from, MessageActor::Dm(m.to), m.time_posted, &dec) {
+                Ok(delivery) if delivery.kind() == PayloadKind::Control => {},
+                Ok(delivery) => match delivery.warning() {
+                    Some(w) => info!("{} ({}, {}) {}", when, who, w, delivery.body()),
+                    None => info!("{} ({}) {}", when, who, delivery.body()),
+                },
+                Err(e) => warn!("{} ({}) [dropped: {}]", when, who, e),
+            },
+            Err(CryptoError::NoSession) => info!("{} ({}) [forward secret, can only be read once]", when, who),
+            Err(e) => warn!("{} ({}) [cannot decrypt: {}]", when, who, e),
+        }
+    }
+    info!("----- end of history with {} -----", uid);
+}
+
+/// `sender_label` plus whatever is off about the delivery.
+fn delivery_label(cfg: &LocalServerEntry, known: &KnownKeys, uid: UserId, delivery: &Delivery) -> String {
+    match delivery.warning() {
+        Some(w) => format!("{}, {}", sender_label(cfg, known, uid), w),
+        None => sender_label(cfg, known, uid),
+    }
+}
+
+/// Handles a control message that arrived as an encrypted DM from `uid`.
+/// Requests for our sender key are collected in `key_requests`, answering them needs the server.
+fn handle_dm_control(
+    cfg: &LocalServerEntry,
+    known: &KnownKeys,
+    groups: &mut GroupKeys,
+    key_requests: &mut Vec<(UserId, GroupId)>,
+    uid: UserId,
+    ctl: DmControl,
+) {
+    match ctl {
+        DmControl::SenderKey(dist) => {
+            if !known.is_trusted(&cfg.http_addr, uid) {
+                error!("Ignored group key from {}: their key changed, check it and run /accept {}", uid, uid);
+                return;
+            }
+            let gid = dist.group;
+            groups.accept(uid, dist);
+            info!("Got group {} key of {}", gid, uid);
+            if let Err(e) = groups.save() {
+                error!("Failed to save group keys: {}", e);
+            }
+        }
+        DmControl::SenderKeyRequest(gid) => {
+            info!("{} asked for our group {} key", uid, gid);
+            key_requests.push((uid, gid));
+        }
+    }
+}
+
+/// How a sender is shown next to their messages.
+fn sender_label(cfg: &LocalServerEntry, known: &KnownKeys, uid: UserId) -> String {
+    if known.is_verified(&cfg.http_addr, uid) {
+        format!("{} \u{2713}", uid)
+    } else if known.is_trusted(&cfg.http_addr, uid) {
+        uid.to_string()
+    } else {
+        format!("{}, UNTRUSTED KEY", uid)
+    }
+}
+
+fn presence_label(online: bool) -> &'static str {
+    if online {
+        "online"
+    } else {
+        "offline"
+    }
+}
+
+#[derive(Debug)]
+pub enum DeviceError {
+    RequestFailed,
+    Rejected(reqwest::StatusCode),
+    DeserializeFailed,
+}
+
+/// Link requests waiting for the main device, see `PairingCode::matches`.
+async fn get_pending_devices(
+    cfg: &LocalServerEntry,
+    client: &reqwest::Client,
+    lt: &LoginToken,
+) -> Result<Vec<PendingDevice>, DeviceError> {
+    let resp = client
+        .get(&format!("{}{}", cfg.http_addr, "devices/pending"))
+        .header("Authorization", lt.tk.as_str())
+        .send()
+        .await
+        .map_err(|_| DeviceError::RequestFailed)?;
+    if !resp.status().is_success() {
+        return Err(DeviceError::Rejected(resp.status()));
+    }
+    resp.json()
+        .map_err(|_| DeviceError::DeserializeFailed)
+        .await
+}
+
+async fn post_pending_device(
+    cfg: &LocalServerEntry,
+    client: &reqwest::Client,
+    lt: &LoginToken,
+    request: &PendingDevice,
+) -> Result<(), DeviceError> {
+    let resp = client
+        .post(&format!("{}{}", cfg.http_addr, "devices/pending"))
+        .header("Authorization", lt.tk.as_str())
+        .body(serde_json::to_string(request).unwrap())
+        .send()
+        .await
+        .map_err(|_| DeviceError::RequestFailed)?;
+    if resp.status().is_success() {
+        Ok(())
+    } else {
+        Err(DeviceError::Rejected(resp.status()))
+    }
+}
+
+/// Leaves what a new device needs to log in on the server, see `PairingCode::seal_offer`.
+async fn post_pairing_offer(
+    cfg: &LocalServerEntry,
+    client: &reqwest::Client,
+    lt: &LoginToken,
+    offer: &PairingOffer,
+) -> Result<(), DeviceError> {
+    let resp = client
+        .post(&format!("{}{}", cfg.http_addr, "devices/offers"))
+        .header("Authorization", lt.tk.as_str())
+        .body(serde_json::to_string(offer).unwrap())
+        .send()
+        .await
+        .map_err(|_| DeviceError::RequestFailed)?;
+    if resp.status().is_success() {
+        Ok(())
+    } else {
+        Err(DeviceError::Rejected(resp.status()))
+    }
+}
+
+/// Fetched by the new device before it has an account config, so there is no login token yet.
+async fn get_pairing_offer(http_addr: &str, client: &reqwest::Client, id: &str) -> Result<PairingOffer, DeviceError> {
+    let resp = client
+        .get(&format!("{}{}/{}", http_addr, "devices/offers", id))
+        .send()
+        .await
+        .map_err(|_| DeviceError::RequestFailed)?;
+    if !resp.status().is_success() {
+        return Err(DeviceError::Rejected(resp.status()));
+    }
+    resp.json()
+        .map_err(|_| DeviceError::DeserializeFailed)
+        .await
+}
+
+/// Publishes an approved device in `PublicUserRecord::devices`.
+async fn upload_device(
+    cfg: &LocalServerEntry,
+    client: &reqwest::Client,
+    lt: &LoginToken,
+    device: &DeviceKey,
+) -> Result<(), DeviceError> {
+    let resp = client
+        .post(&format!("{}{}", cfg.http_addr, "devices"))
+        .header("Authorization", lt.tk.as_str())
+        .body(serde_json::to_string(device).unwrap())
+        .send()
+        .await
+        .map_err(|_| DeviceError::RequestFailed)?;
+    if resp.status().is_success() {
+        Ok(())
+    } else {
+        Err(DeviceError::Rejected(resp.status()))
+    }
+}
+
+#[derive(Debug)]
+pub enum PresenceError {
+    RequestFailed,
+    Rejected(reqwest::StatusCode),
+}
+
+async fn update_presence(
+    cfg: &LocalServerEntry,
+    client: &reqwest::Client,
+    lt: &LoginToken,
+    request: &PresenceUpdateRequest,
+) -> Result<(), PresenceError> {
+    let resp = client
+        .put(&format!("{}{}", cfg.http_addr, "me/presence"))
+        .header("Authorization", lt.tk.as_str())
+        .body(serde_json::to_string(request).unwrap())
+        .send()
+        .await
+        .map_err(|_| PresenceError::RequestFailed)?;
+    if resp.status().is_success() {
+        Ok(())
+    } else {
+        Err(PresenceError::Rejected(resp.status()))
+    }
+}
+
+#[derive(Debug)]
+pub enum LoginError {
+    RequestFailed,
+    Rejected(reqwest::StatusCode),
+    DeserializeFailed,
+}
+
+async fn login(
+    cfg: &LocalServerEntry,
+    client: &reqwest::Client,
+    phash: SecretString,
+) -> Result<LoginToken, LoginError> {
+    let resp = client
+        .post(&format!("{}{}", cfg.http_addr, "login"))
+        .body(
+            serde_json::to_string(&LoginRequest {
+                email: cfg.email.to_owned(),
+                password_hash: phash,
+            })
+            .unwrap(),
+        )
+        .send()
+        .await
+        .map_err(|_| LoginError::RequestFailed)?;
+    if !resp.status().is_success() {
+        return Err(LoginError::Rejected(resp.status()));
+    }
+    resp.json()
+        .map_err(|_| LoginError::DeserializeFailed)
+        .await
+}
+
+fn prompt_password(cfg: &LocalServerEntry) -> std::io::Result<SecretString> {
+    prompt_secret(&format!("Password for {}: ", &cfg.email))
+}
+
+fn prompt_secret(prompt: &str) -> std::io::Result<SecretString> {
+    rpassword::read_password_from_tty(Some(prompt)).map(SecretString::from)
+}
+
+#[derive(Debug)]
+pub enum UploadPubkeyError {
+    RequestFailed,
+    Rejected(reqwest::StatusCode),
+}
+
+async fn upload_pubkey(
+    cfg: &LocalServerEntry,
+    client: &reqwest::Client,
+    lt: &LoginToken,
+    identity: &LocalIdentity,
+    notice: KeyChangeNotice,
+) -> Result<(), UploadPubkeyError> {
+    let resp = client
+        .post(&format!("{}{}", cfg.http_addr, "pubkey"))
+        .header("Authorization", lt.tk.as_str())
+        .body(
+            serde_json::to_string(&PubkeyUpdateRequest {
+                pubkey: identity.pubkey.to_owned(),
+                notice,
+            })
+            .unwrap(),
+        )
+        .send()
+        .await
+        .map_err(|_| UploadPubkeyError::RequestFailed)?;
+    if resp.status().is_success() {
+        Ok(())
+    } else {
+        Err(UploadPubkeyError::Rejected(resp.status()))
+    }
+}
+
+#[derive(Debug)]
+pub enum PrekeyError {
+    RequestFailed,
+    Rejected(reqwest::StatusCode),
+    DeserializeFailed,
+}
+
+async fn upload_prekey(
+    cfg: &LocalServerEntry,
+    client: &reqwest::Client,
+    lt: &LoginToken,
+    prekey: &SignedPrekey,
+) -> Result<(), PrekeyError> {
+    let resp = client
+        .post(&format!("{}{}", cfg.http_addr, "prekey"))
+        .header("Authorization", lt.tk.as_str())
+        .body(serde_json::to_string(prekey).unwrap())
+        .send()
+        .await
+        .map_err(|_| PrekeyError::RequestFailed)?;
+    if resp.status().is_success() {
+        Ok(())
+    } else {
+        Err(PrekeyError::Rejected(resp.status()))
+    }
+}
+
+/// Seals `text` of `kind` from us to `to` and encrypts it, starting a forward-secret session if
+/// there is none yet. `cfg.uid` must be known.
+async fn encrypt_dm(
+    cfg: &LocalServerEntry,
+    client: &reqwest::Client,
+    key: &InMemoryKey,
+    sessions: &mut Sessions,
+    replay: &mut ReplayGuard,
+    to: &CachedUser,
+    kind: PayloadKind,
+    text: &str,
+) -> Result<ClientMessage, CryptoError> {
+    let prekey = if sessions.needs_prekey(to) {
+        match get_prekey(cfg, client, &to.uid()).await {
+            Ok(prekey) => Some(prekey),
+            Err(e) => {
+                warn!("No signed prekey for {}, sending without forward secrecy: {:?}", to.uid(), e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let sealed = replay.seal(&cfg.http_addr, cfg.uid.unwrap(), MessageActor::Dm(to.uid()), kind, text);
+    let enc = sessions.encrypt(key, to, prekey.as_ref(), &sealed);
+    if let Err(e) = sessions.save() {
+        error!("Failed to save sessions: {}", e);
+    }
+    if let Err(e) = replay.save() {
+        error!("Failed to save replay counters: {}", e);
+    }
+    enc
+}
+
+#[derive(Debug)]
+pub enum GroupError {
+    RequestFailed,
+    Rejected(reqwest::StatusCode),
+    DeserializeFailed,
+}
+
+/// Group details, members only see `GroupRecord::members`.
+async fn get_group(
+    cfg: &LocalServerEntry,
+    client: &reqwest::Client,
+    lt: &LoginToken,
+    gid: GroupId,
+) -> Result<GroupRecord, GroupError> {
+    let resp = client
+        .get(&format!("{}{}/{}", cfg.http_addr, "groups", gid))
+        .header("Authorization", lt.tk.as_str())
+        .send()
+        .await
+        .map_err(|_| GroupError::RequestFailed)?;
+    if !resp.status().is_success() {
+        return Err(GroupError::Rejected(resp.status()));
+    }
+    resp.json()
+        .map_err(|_| GroupError::DeserializeFailed)
+        .await
+}
+
+async fn create_group(
+    cfg: &LocalServerEntry,
+    client: &reqwest::Client,
+    lt: &LoginToken,
+    request: &CreateGroupRequest,
+) -> Result<GroupRecord, GroupError> {
+    let resp = client
+        .post(&format!("{}{}", cfg.http_addr, "groups"))
+        .header("Authorization", lt.tk.as_str())
+        .body(serde_json::to_string(request).unwrap())
+        .send()
+        .await
+        .map_err(|_| GroupError::RequestFailed)?;
+    if !resp.status().is_success() {
+        return Err(GroupError::Rejected(resp.status()));
+    }
+    resp.json()
+        .map_err(|_| GroupError::DeserializeFailed)
+        .await
+}
+
+async fn invite_to_group(
+    cfg: &LocalServerEntry,
+    client: &reqwest::Client,
+    lt: &LoginToken,
+    gid: GroupId,
+    request: &GroupInviteRequest,
+) -> Result<(), GroupError> {
+    let resp = client
+        .post(&format!("{}{}/{}/members", cfg.http_addr, "groups", gid))
+        .header("Authorization", lt.tk.as_str())
+        .body(serde_json::to_string(request).unwrap())
+        .send()
+        .await
+        .map_err(|_| GroupError::RequestFailed)?;
+    if resp.status().is_success() {
+        Ok(())
+    } else {
+        Err(GroupError::Rejected(resp.status()))
+    }
+}
+
+/// Only ourselves, i.e. leaving, unless the server lets members remove each other.
+async fn remove_from_group(
+    cfg: &LocalServerEntry,
+    client: &reqwest::Client,
+    lt: &LoginToken,
+    gid: GroupId,
+    uid: UserId,
+) -> Result<(), GroupError> {
+    let resp = client
+        .delete(&format!("{}{}/{}/members/{}", cfg.http_addr, "groups", gid, uid))
+        .header("Authorization", lt.tk.as_str())
+        .send()
+        .await
+        .map_err(|_| GroupError::RequestFailed)?;
+    if resp.status().is_success() {
+        Ok(())
+    } else {
+        Err(GroupError::Rejected(resp.status()))
+    }
+}
+
+async fn set_group_motd(
+    cfg: &LocalServerEntry,
+    client: &reqwest::Client,
+    lt: &LoginToken,
+    gid: GroupId,
+    request: &SetMotdRequest,
+) -> Result<(), GroupError> {
+    let resp = client
+        .put(&format!("{}{}/{}/motd", cfg.http_addr, "groups", gid))
+        .header("Authorization", lt.tk.as_str())
+        .body(serde_json::to_string(request).unwrap())
+        .send()
+        .await
+        .map_err(|_| GroupError::RequestFailed)?;
+    if resp.status().is_success() {
+        Ok(())
+    } else {
+        Err(GroupError::Rejected(resp.status()))
+    }
+}
+
+async fn get_prekey(
+    cfg: &LocalServerEntry,
+    client: &reqwest::Client,
+    uid: &UserId,
+) -> Result<SignedPrekey, PrekeyError> {
+    let resp = client
+        .get(&format!("{}{}/{}/prekey", cfg.http_addr, "users", uid))
+        .send()
+        .await
+        .map_err(|_| PrekeyError::RequestFailed)?;
+    if !resp.status().is_success() {
+        return Err(PrekeyError::Rejected(resp.status()));
+    }
+    resp.json()
+        .map_err(|_| PrekeyError::DeserializeFailed)
+        .await
+}
+
+#[derive(Debug)]
+pub enum KdfUpgradeError {
+    Crypto(CryptoError),
+    RequestFailed,
+    /// Most likely the server doesn't know the endpoint yet.
+    Rejected(reqwest::StatusCode),
+}
+
+/// Asks the server to swap the legacy password hash for the one derived with `kdf`.
+/// Keep `kdf` in the config before calling, the server may switch without the answer arriving.
+async fn upgrade_kdf(
+    cfg: &LocalServerEntry,
+    client: &reqwest::Client,
+    lt: &LoginToken,
+    kdf: &PasswordKdf,
+    password: &str,
+) -> Result<(), KdfUpgradeError> {
+    let req = ChangePasswordRequest {
+        email: cfg.email.to_owned(),
+        old_password_hash: cfg.kdf.derive(password).map_err(KdfUpgradeError::Crypto)?,
+        new_password_hash: kdf.derive(password).map_err(KdfUpgradeError::Crypto)?,
+    };
+    let resp = client
+        .post(&format!("{}{}", cfg.http_addr, "password"))
+        .header("Authorization", lt.tk.as_str())
+        .body(serde_json::to_string(&req).unwrap())
+        .send()
+        .await
+        .map_err(|_| KdfUpgradeError::RequestFailed)?;
+    if resp.status().is_success() {
+        Ok(())
+    } else {
+        Err(KdfUpgradeError::Rejected(resp.status()))
+    }
+}
+
+/// Returns the cached record of `uid`, fetching, validating and pinning it first if needed.
+/// A user whose key can't be used is never cached, so nothing gets sent to or accepted from them.
+async fn fetch_user<'a>(
+    cfg: &LocalServerEntry,
+    client: &reqwest::Client,
+    known: &mut KnownKeys,
+    log: &mut SecurityLog,
+    cache_users: &'a mut HashMap<UserId, CachedUser>,
+    uid: UserId,
+) -> Result<&'a CachedUser, GetUserError> {
+    if !cache_users.contains_key(&uid) {
+        let pur = CachedUser::new(get_user(cfg, client, &uid).await?).map_err(GetUserError::InvalidKey)?;
+        check_pin(cfg, known, log, &pur.record);
+        cache_users.insert(uid, pur);
+        info!("Added user cache {}", uid);
+    }
+    Ok(&cache_users[&uid])
+}
+
+#[derive(Debug)]
+pub enum GetUserError {
+    RequestFailed,
+    DeserializeFailed,
+    /// The server handed out a key that doesn't parse or is too weak, see `PublicIdentity::validate`.
+    InvalidKey(CryptoError),
+}
+
+/// Own record, as the server knows the logged in account.
+async fn get_self(
+    cfg: &LocalServerEntry,
+    client: &reqwest::Client,
+    lt: &LoginToken,
+) -> Result<PublicUserRecord, GetUserError> {
+    let resp = client
+        .get(&format!("{}{}", cfg.http_addr, "me"))
+        .header("Authorization", lt.tk.as_str())
+        .send()
+        .await
+        .map_err(|_| GetUserError::RequestFailed)?;
+    resp.json()
+        .map_err(|_| GetUserError::DeserializeFailed)
+        .await
+}
+
+async fn get_user(
+    cfg: &LocalServerEntry,
+    client: &reqwest::Client,
+    uid: &UserId,
+) -> Result<PublicUserRecord, GetUserError> {
+    let resp = client
+        .get(&format!("{}{}/{}", cfg.http_addr, "users", uid))
+        .send()
+        .await
+        .map_err(|_| GetUserError::RequestFailed)?;
+    resp.json()
+        .map_err(|_| GetUserError::DeserializeFailed)
+        .await
+}
+
+fn print_parse_e(e: CliParseError) {
+    match e {
+        CliParseError::CannotChatNow(_) => {
+            error!("Cannot chat right now");
+        }
         CliParseError::Empty => {
             warn!("Empty line");
         }
@@ -323,6 +1957,12 @@ fn print_parse_e(e: CliParseError) {
         CliParseError::MissingExpected(v) => {
             error!("EOL but expected {}", &v);
         }
+        CliParseError::InvalidValue(k, v) => {
+            error!("Invalid value {} for {}", &v, &k);
+        }
+        CliParseError::InvalidDate(d) => {
+            error!("Invalid date {}", &d);
+        }
         CliParseError::NotImpl => {
             warn!("Command not implemented");
         }
@@ -0,0 +1,241 @@
+use crate::imports::*;
+use crate::symbols::*;
+
+/// How long to wait before writing a message again that didn't make it to the socket.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+/// How long to wait for an ack before writing the message again.
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Writes of a message before it is reported as failed.
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Our own number for an outgoing message, the server's `UserMessageId` is only known once it acked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LocalMessageId(u64);
+
+impl Display for LocalMessageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Which ack a message waits for, `MessageSent` for DMs and `GroupMessageSent` for groups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckKind {
+    Dm,
+    Group,
+}
+
+impl AckKind {
+    fn of(to: MessageActor) -> Self {
+        match to {
+            MessageActor::Dm(_) => AckKind::Dm,
+            MessageActor::Group(_) => AckKind::Group,
+        }
+    }
+}
+
+struct Outgoing {
+    to: MessageActor,
+    payload: WsServerboundPayload,
+    attempts: u32,
+    last_attempt: Instant,
+    /// Reached the socket with the last attempt, so it is waiting for an ack.
+    written: bool,
+}
+
+/// What `Outbox::due` found on a tick.
+#[derive(Default)]
+pub struct DueMessages {
+    /// Not written or not acknowledged in time, write them now.
+    pub resend: Vec<(LocalMessageId, WsServerboundPayload)>,
+    /// Out of attempts, given up on.
+    pub failed: Vec<(LocalMessageId, MessageActor, String)>,
+}
+
+/// Outgoing messages the server hasn't acknowledged yet.
+///
+/// `MessageSent` and `GroupMessageSent` don't say which message they are for, so acks are matched
+/// to messages of their kind in the order they were written to the socket. A message without an
+/// ack within `ACK_TIMEOUT` is taken as dropped and written again. That is safe since it is sealed
+/// with a counter: recipients who got the first copy skip the second one as a duplicate.
+#[derive(Default)]
+pub struct Outbox {
+    next_id: u64,
+    messages: HashMap<LocalMessageId, Outgoing>,
+    /// Written to the socket and not acked yet, oldest first.
+    written_dms: VecDeque<LocalMessageId>,
+    written_groups: VecDeque<LocalMessageId>,
+}
+
+impl Outbox {
+    /// Queues `payload` as written, returns its id and what to send now.
+    /// Call `send_failed` if it didn't make it to the socket.
+    pub fn push(&mut self, to: MessageActor, payload: WsServerboundPayload) -> (LocalMessageId, WsServerboundPayload) {
+        let id = LocalMessageId(self.next_id);
+        self.next_id += 1;
+        self.messages.insert(
+            id,
+            Outgoing {
+                to,
+                payload: payload.clone(),
+                attempts: 1,
+                last_attempt: Instant::now(),
+                written: true,
+            },
+        );
+        self.written(AckKind::of(to)).push_back(id);
+        (id, payload)
+    }
+
+    /// The attempt never made it to the socket, so no ack will come for it.
+    /// The message is written again after `RETRY_DELAY`.
+    pub fn send_failed(&mut self, id: LocalMessageId) {
+        if let Some(out) = self.messages.get_mut(&id) {
+            out.written = false;
+            let to = out.to;
+            self.unqueue(to, id);
+        }
+    }
+
+    /// Matches an ack to the oldest written message of its kind. `None` if there was none,
+    /// i.e. the server acked something we didn't send, or an earlier write taken as dropped.
+    pub fn acked(&mut self, kind: AckKind) -> Option<(LocalMessageId, MessageActor)> {
+        let id = self.written(kind).pop_front()?;
+        let out = self.messages.remove(&id)?;
+        Some((id, out.to))
+    }
+
+    pub fn due(&mut self) -> DueMessages {
+        let now = Instant::now();
+        let mut due = DueMessages::default();
+        let mut ids: Vec<LocalMessageId> = self.messages.keys().copied().collect();
+        // resends are written in this order, keep it the order they were queued in
+        ids.sort();
+        for id in ids {
+            let out = self.messages.get_mut(&id).unwrap();
+            let wait = if out.written { ACK_TIMEOUT } else { RETRY_DELAY };
+            if now.duration_since(out.last_attempt) < wait {
+                continue;
+            }
+            let to = out.to;
+            if out.attempts >= MAX_ATTEMPTS {
+                let reason = if out.written {
+                    format!("not acknowledged after {} attempts", out.attempts)
+                } else {
+                    format!("not sent after {} attempts", out.attempts)
+                };
+                due.failed.push((id, to, reason));
+                self.messages.remove(&id);
+                self.unqueue(to, id);
+                continue;
+            }
+            out.attempts += 1;
+            out.last_attempt = now;
+            out.written = true;
+            due.resend.push((id, out.payload.clone()));
+            // an ack for the earlier write isn't coming anymore, the new one goes to the back
+            self.unqueue(to, id);
+            self.written(AckKind::of(to)).push_back(id);
+        }
+        due
+    }
+
+    fn written(&mut self, kind: AckKind) -> &mut VecDeque<LocalMessageId> {
+        match kind {
+            AckKind::Dm => &mut self.written_dms,
+            AckKind::Group => &mut self.written_groups,
+        }
+    }
+
+    fn unqueue(&mut self, to: MessageActor, id: LocalMessageId) {
+        let written = self.written(AckKind::of(to));
+        if let Some(pos) = written.iter().position(|w| *w == id) {
+            written.remove(pos);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(n: u32) -> WsServerboundPayload {
+        WsServerboundPayload::NewUserMessage {
+            to: UserId::from(n),
+            content: ClientMessage::from(String::new()),
+        }
+    }
+
+    fn age(outbox: &mut Outbox, id: LocalMessageId, by: Duration) {
+        let out = outbox.messages.get_mut(&id).unwrap();
+        out.last_attempt -= by;
+    }
+
+    fn group_payload(n: u32) -> WsServerboundPayload {
+        WsServerboundPayload::NewGroupMessage {
+            to: GroupId::from(n),
+            content: ClientMessage::from(String::new()),
+        }
+    }
+
+    #[test]
+    fn acks_match_written_messages_in_order() {
+        let mut outbox = Outbox::default();
+        let (a, _) = outbox.push(MessageActor::Dm(UserId::from(1)), payload(1));
+        let (b, _) = outbox.push(MessageActor::Dm(UserId::from(2)), payload(2));
+        let (c, _) = outbox.push(MessageActor::Dm(UserId::from(3)), payload(3));
+        outbox.send_failed(a);
+        assert_eq!(outbox.acked(AckKind::Dm).map(|(id, _)| id), Some(b));
+        assert_eq!(outbox.acked(AckKind::Dm).map(|(id, _)| id), Some(c));
+        assert!(outbox.acked(AckKind::Dm).is_none());
+    }
+
+    #[test]
+    fn dm_and_group_acks_are_matched_separately() {
+        let mut outbox = Outbox::default();
+        let (dm, _) = outbox.push(MessageActor::Dm(UserId::from(1)), payload(1));
+        let (group, _) = outbox.push(MessageActor::Group(GroupId::from(1)), group_payload(1));
+        assert_eq!(outbox.acked(AckKind::Group).map(|(id, _)| id), Some(group));
+        assert!(outbox.acked(AckKind::Group).is_none());
+        assert_eq!(outbox.acked(AckKind::Dm).map(|(id, _)| id), Some(dm));
+    }
+
+    #[test]
+    fn unacknowledged_messages_are_written_again() {
+        let mut outbox = Outbox::default();
+        let (a, _) = outbox.push(MessageActor::Dm(UserId::from(1)), payload(1));
+        let (b, _) = outbox.push(MessageActor::Dm(UserId::from(2)), payload(2));
+        age(&mut outbox, a, ACK_TIMEOUT);
+        let due = outbox.due();
+        assert!(due.failed.is_empty());
+        assert_eq!(due.resend.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![a]);
+        // written once more, and waiting for the next timeout
+        assert!(outbox.due().resend.is_empty());
+        // the copy went out after b, so it is acked after b
+        assert_eq!(outbox.acked(AckKind::Dm).map(|(id, _)| id), Some(b));
+        assert_eq!(outbox.acked(AckKind::Dm).map(|(id, _)| id), Some(a));
+        assert!(outbox.acked(AckKind::Dm).is_none());
+    }
+
+    #[test]
+    fn messages_fail_after_max_attempts() {
+        let mut outbox = Outbox::default();
+        let (a, _) = outbox.push(MessageActor::Dm(UserId::from(1)), payload(1));
+        let (b, _) = outbox.push(MessageActor::Group(GroupId::from(1)), group_payload(1));
+        outbox.send_failed(a);
+        for _ in 1..MAX_ATTEMPTS {
+            assert!(outbox.due().resend.is_empty());
+            age(&mut outbox, a, RETRY_DELAY);
+            age(&mut outbox, b, ACK_TIMEOUT);
+            assert_eq!(outbox.due().resend.len(), 2);
+            outbox.send_failed(a);
+        }
+        age(&mut outbox, a, RETRY_DELAY);
+        age(&mut outbox, b, ACK_TIMEOUT);
+        let due = outbox.due();
+        assert!(due.resend.is_empty());
+        assert_eq!(due.failed.iter().map(|(id, _, _)| *id).collect::<Vec<_>>(), vec![a, b]);
+        assert!(outbox.acked(AckKind::Dm).is_none());
+        assert!(outbox.acked(AckKind::Group).is_none());
+    }
+}
@@ -0,0 +1,662 @@
+use crate::imports::*;
+use crate::symbols::*;
+use openssl::{
+    hash::{hash, MessageDigest},
+    pkey::{HasPublic, Id, PKey, Private, Public},
+};
+
+/// `version` of a `RatchetMessage`, see `ENVELOPE_VERSION` for the ones taken by envelopes.
+/// 3 was the format before plaintexts were padded.
+pub const RATCHET_VERSION: u8 = 6;
+/// Most message keys skipped in one go, e.g. for messages the server never delivered.
+const MAX_SKIP: u32 = 1000;
+/// Skipped message keys kept per session before the oldest are dropped.
+const MAX_SKIPPED_KEYS: usize = 2000;
+/// More than one session per peer exists when both sides start one at the same time.
+const MAX_SESSIONS_PER_PEER: usize = 3;
+/// Signed prekeys are replaced after this long...
+const PREKEY_MAX_AGE_DAYS: i64 = 7;
+/// ...and the previous ones kept for sessions started with them in the meantime.
+const PREKEYS_KEPT: usize = 3;
+
+/// What travels inside a `ClientMessage` once a session exists, serialized as JSON.
+/// Binary fields are hex encoded.
+#[derive(Serialize, Deserialize)]
+struct RatchetMessage {
+    version: u8,
+    session: String,
+    header: RatchetHeader,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    init: Option<SessionInit>,
+    ciphertext: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct RatchetHeader {
+    /// Sender's current ratchet key, hex DER.
+    dh: String,
+    /// Length of the sender's previous sending chain.
+    pn: u32,
+    n: u32,
+}
+
+/// X3DH parameters, attached by the initiator to every message until the peer replies.
+#[derive(Serialize, Deserialize, Clone)]
+struct SessionInit {
+    /// Initiator's ephemeral X25519 key, hex DER.
+    ephemeral: String,
+    /// Which of the responder's signed prekeys was used, see `prekey_id`.
+    prekey: String,
+}
+
+/// Just enough to tell a `RatchetMessage` from an `Envelope`.
+#[derive(Deserialize)]
+struct Versioned {
+    version: u8,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct SkippedKey {
+    dh: String,
+    n: u32,
+    mk: String,
+}
+
+/// Double Ratchet state of one session, see https://signal.org/docs/specifications/doubleratchet/
+#[derive(Serialize, Deserialize, Clone)]
+struct RatchetState {
+    id: String,
+    /// Initiator's identity fingerprint followed by the responder's.
+    ad: String,
+    root_key: String,
+    /// Own ratchet key, PEM.
+    dhs: String,
+    dhs_pub: String,
+    dhr: Option<String>,
+    /// `dhr` before the last DH ratchet step. Whatever is left of that chain is in `skipped`.
+    #[serde(default)]
+    prev_dhr: Option<String>,
+    cks: Option<String>,
+    ckr: Option<String>,
+    ns: u32,
+    nr: u32,
+    pn: u32,
+    skipped: Vec<SkippedKey>,
+    init: Option<SessionInit>,
+    last_used: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct OwnPrekey {
+    /// PEM.
+    private: String,
+    /// Hex DER.
+    public: String,
+    created: DateTime<Utc>,
+    #[serde(default)]
+    published: bool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SessionData {
+    /// Newest first.
+    prekeys: Vec<OwnPrekey>,
+    /// Keyed by the peer's identity fingerprint, most recently used first.
+    peers: HashMap<String, Vec<RatchetState>>,
+}
+
+/// Forward-secret sessions with contacts, X3DH to start them and the Double Ratchet after that.
+///
+/// Message keys are deleted as soon as they are used, so a stolen config can't decrypt earlier
+/// messages, and every reply mixes in fresh DH output, so a session heals after a compromise.
+/// Only available when both sides have curve identities and no linked devices, everything else
+/// falls back to `InMemoryKey::encrypt`.
+pub struct Sessions {
+    path: PathBuf,
+    /// `None` for RSA identities.
+    storage_key: Option<SecretBytes>,
+    /// Own account has more than one device. Sessions only reach one of them, and every device
+    /// would overwrite the others' prekey, so no new ones are started.
+    linked: bool,
+    data: SessionData,
+}
+
+impl Sessions {
+    /// Kept next to the config, e.g. `alice.json` -> `alice.sessions.json`.
+    pub fn path_for(cfg_path: &Path) -> PathBuf {
+        cfg_path.with_extension("sessions.json")
+    }
+
+    pub fn load(path: PathBuf, key: &InMemoryKey) -> Result<Self, ConfigError> {
+        let storage_key = key.private().storage_key(b"yap session store").unwrap_or_else(|e| {
+            error!("Cannot derive session storage key, sessions disabled: {}", e);
+            None
+        });
+        let mut data = SessionData::default();
+        if let Some(sk) = &storage_key {
+            if path.exists() {
+                let sealed: SealedState = load_private_json(&path)?;
+                match sealed.open(sk, "sessions") {
+                    Ok(d) => data = d,
+                    Err(e) => {
+                        // e.g. after rotate-key, the old sessions belonged to the old identity anyway
+                        warn!("Cannot read sessions in {:?}, starting over: {}", &path, e);
+                    }
+                }
+            }
+        }
+        Ok(Self {
+            path,
+            storage_key,
+            linked: false,
+            data,
+        })
+    }
+
+    /// Call once it is known whether the own account has linked devices.
+    /// Existing sessions can still be read, but nothing is sent with them anymore.
+    pub fn set_linked(&mut self, linked: bool) {
+        self.linked = linked;
+    }
+
+    pub fn save(&self) -> Result<(), ConfigError> {
+        if let Some(sk) = &self.storage_key {
+            let sealed = SealedState::seal(sk, "sessions", &self.data)
+                .map_err(|e| ConfigError::Io(std::io::Error::other(e.to_string())))?;
+            save_private_json(&self.path, &sealed)?;
+        }
+        Ok(())
+    }
+
+    /// Whether a session with `to` could be started but doesn't exist yet,
+    /// i.e. their signed prekey should be fetched before calling `encrypt`.
+    pub fn needs_prekey(&self, to: &CachedUser) -> bool {
+        self.usable_with(to) && !self.data.peers.contains_key(&to.fingerprint)
+    }
+
+    fn usable_with(&self, to: &CachedUser) -> bool {
+        self.storage_key.is_some()
+            && !self.linked
+            && to.devices.is_empty()
+            && to.identity.algorithm() == KeyAlgorithm::Curve25519
+    }
+
+    /// Encrypts with the session with `to`, starting one from `prekey` if there is none.
+    /// Falls back to a plain envelope if no session can be used.
+    pub fn encrypt(
+        &mut self,
+        key: &InMemoryKey,
+        to: &CachedUser,
+        prekey: Option<&SignedPrekey>,
+        msg: &str,
+    ) -> Result<ClientMessage, CryptoError> {
+        if !self.usable_with(to) {
+            return key.encrypt(to, msg);
+        }
+        if msg.len() > MAX_MESSAGE_LEN {
+            return Err(CryptoError::Oversize(msg.len()));
+        }
+        let fp = &to.fingerprint;
+        if !self.data.peers.contains_key(fp) {
+            match prekey {
+                Some(prekey) => {
+                    let state = initiate(key, to, prekey)?;
+                    self.data.peers.insert(fp.clone(), vec![state]);
+                }
+                None => return key.encrypt(to, msg),
+            }
+        }
+        let sessions = self.data.peers.get_mut(fp).ok_or(CryptoError::NoSession)?;
+        let state = sessions.first_mut().ok_or(CryptoError::NoSession)?;
+        let mut next = state.clone();
+        let (header, ciphertext) = next.encrypt(&key.padding().pad(msg.as_bytes()))?;
+        next.last_used = Utc::now();
+        let out = RatchetMessage {
+            version: RATCHET_VERSION,
+            session: next.id.clone(),
+            header,
+            init: next.init.clone(),
+            ciphertext: hex::encode(ciphertext),
+        };
+        *state = next;
+        let serialized = serde_json::to_string(&out).map_err(|_| CryptoError::Decode("ratchet message".to_owned()))?;
+        Ok(ClientMessage::from(serialized))
+    }
+
+    /// Decrypts session messages, and anything else with `InMemoryKey::decrypt`.
+    pub fn decrypt(
+        &mut self,
+        key: &InMemoryKey,
+        from: &CachedUser,
+        msg: ClientMessage,
+    ) -> Result<String, CryptoError> {
+        let raw = msg.to_string();
+        let version = serde_json::from_str::<Versioned>(&raw)
+            .map_err(|_| CryptoError::Decode("message".to_owned()))?
+            .version;
+        if version != RATCHET_VERSION {
+            return key.decrypt(from, msg);
+        }
+        if self.storage_key.is_none() {
+            return Err(CryptoError::NoSession);
+        }
+        let m: RatchetMessage =
+            serde_json::from_str(&raw).map_err(|_| CryptoError::Decode("ratchet message".to_owned()))?;
+        if m.ciphertext.len() / 2 > MAX_PADDED_LEN + AEAD_TAG_LEN {
+            return Err(CryptoError::Oversize(m.ciphertext.len() / 2 - AEAD_TAG_LEN));
+        }
+        let ciphertext = unhex("ciphertext", &m.ciphertext)?;
+        let existing = self
+            .data
+            .peers
+            .get(&from.fingerprint)
+            .and_then(|sessions| sessions.iter().find(|s| s.id == m.session))
+            .cloned();
+        let mut state = match (existing, &m.init) {
+            (Some(state), _) => state,
+            (None, Some(init)) => self.respond(key, from, &m.session, init)?,
+            (None, None) => return Err(CryptoError::NoSession),
+        };
+        // work on a copy, a forged message must not advance the real state
+        let plaintext = state.decrypt(&m.header, &ciphertext)?;
+        // the peer replied, so it has the session
+        if m.init.is_none() {
+            state.init = None;
+        }
+        state.last_used = Utc::now();
+        let sessions = self.data.peers.entry(from.fingerprint.clone()).or_default();
+        sessions.retain(|s| s.id != state.id);
+        sessions.insert(0, state);
+        sessions.truncate(MAX_SESSIONS_PER_PEER);
+        String::from_utf8(unpad(plaintext)?).map_err(CryptoError::Utf8)
+    }
+
+    /// Decrypts a message that may have been read before, e.g. from `/history`, without touching
+    /// any session. Session messages fail with `NoSession`, their keys are gone once used.
+    pub fn reread(&self, key: &InMemoryKey, from: &CachedUser, msg: ClientMessage) -> Result<String, CryptoError> {
+        let version = serde_json::from_str::<Versioned>(&msg.to_string())
+            .map_err(|_| CryptoError::Decode("message".to_owned()))?
+            .version;
+        if version == RATCHET_VERSION {
+            return Err(CryptoError::NoSession);
+        }
+        key.decrypt(from, msg)
+    }
+
+    /// Signed prekey to upload if the current one is missing, too old or never made it to the
+    /// server. Call `prekey_published` once the upload went through.
+    pub fn prekey_to_publish(&mut self, key: &InMemoryKey) -> Result<Option<SignedPrekey>, CryptoError> {
+        if self.storage_key.is_none() || self.linked {
+            return Ok(None);
+        }
+        let due = match self.data.prekeys.first() {
+            Some(p) => Utc::now() - p.created >= chrono::Duration::days(PREKEY_MAX_AGE_DAYS),
+            None => true,
+        };
+        if due {
+            let pkey = PKey::generate_x25519().map_err(CryptoError::Internal)?;
+            self.data.prekeys.insert(
+                0,
+                OwnPrekey {
+                    private: private_pem(&pkey)?,
+                    public: public_hex(&pkey)?,
+                    created: Utc::now(),
+                    published: false,
+                },
+            );
+            self.data.prekeys.truncate(PREKEYS_KEPT);
+        }
+        match self.data.prekeys.first() {
+            Some(own) if !own.published => {
+                let signature = key.private().sign(&prekey_bytes(&own.public, &own.created))?;
+                Ok(Some(SignedPrekey {
+                    key: own.public.clone(),
+                    created: own.created,
+                    signature: hex::encode(signature),
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn prekey_published(&mut self) {
+        if let Some(own) = self.data.prekeys.first_mut() {
+            own.published = true;
+        }
+    }
+
+    fn respond(
+        &self,
+        key: &InMemoryKey,
+        peer: &CachedUser,
+        session: &str,
+        init: &SessionInit,
+    ) -> Result<RatchetState, CryptoError> {
+        let (own_dh, their_dh) = curve_dh_keys(key, peer)?;
+        let own_prekey = self
+            .data
+            .prekeys
+            .iter()
+            .find(|p| prekey_id(&p.public).ok().as_deref() == Some(init.prekey.as_str()))
+            .ok_or(CryptoError::NoSession)?;
+        if session_id(&init.ephemeral, &own_prekey.public)? != session {
+            return Err(CryptoError::PaddingOrAuth);
+        }
+        let spk = private_from_pem(&own_prekey.private)?;
+        let ephemeral = public_from_hex(&init.ephemeral)?;
+        let mut ikm = vec![0xFF; 32];
+        ikm.extend(x25519(&spk, their_dh)?);
+        ikm.extend(x25519(own_dh, &ephemeral)?);
+        ikm.extend(x25519(&spk, &ephemeral)?);
+        let sk = hkdf_sha256(&[0; 32], &ikm, b"yap x3dh", AEAD_KEY_LEN)?;
+        Ok(RatchetState {
+            id: session.to_owned(),
+            ad: format!("{}{}", peer.fingerprint, key.fingerprint()),
+            root_key: hex::encode(sk),
+            dhs: own_prekey.private.clone(),
+            dhs_pub: own_prekey.public.clone(),
+            dhr: None,
+            prev_dhr: None,
+            cks: None,
+            ckr: None,
+            ns: 0,
+            nr: 0,
+            pn: 0,
+            skipped: Vec::new(),
+            init: None,
+            last_used: Utc::now(),
+        })
+    }
+}
+
+/// Starts a session as the initiator, see X3DH.
+fn initiate(key: &InMemoryKey, peer: &CachedUser, prekey: &SignedPrekey) -> Result<RatchetState, CryptoError> {
+    let (own_dh, their_dh) = curve_dh_keys(key, peer)?;
+    let signature = unhex("prekey signature", &prekey.signature)?;
+    if !peer.identity.verify(&prekey_bytes(&prekey.key, &prekey.created), &signature) {
+        return Err(CryptoError::PaddingOrAuth);
+    }
+    let spk = public_from_hex(&prekey.key)?;
+    let ephemeral = PKey::generate_x25519().map_err(CryptoError::Internal)?;
+    let mut ikm = vec![0xFF; 32];
+    ikm.extend(x25519(own_dh, &spk)?);
+    ikm.extend(x25519(&ephemeral, their_dh)?);
+    ikm.extend(x25519(&ephemeral, &spk)?);
+    let sk = hkdf_sha256(&[0; 32], &ikm, b"yap x3dh", AEAD_KEY_LEN)?;
+    let dhs = PKey::generate_x25519().map_err(CryptoError::Internal)?;
+    let (root_key, cks) = kdf_rk(&sk, &x25519(&dhs, &spk)?)?;
+    let init = SessionInit {
+        ephemeral: public_hex(&ephemeral)?,
+        prekey: prekey_id(&prekey.key)?,
+    };
+    Ok(RatchetState {
+        id: session_id(&init.ephemeral, &prekey.key)?,
+        ad: format!("{}{}", key.fingerprint(), peer.fingerprint),
+        root_key: hex::encode(root_key),
+        dhs: private_pem(&dhs)?,
+        dhs_pub: public_hex(&dhs)?,
+        dhr: Some(prekey.key.clone()),
+        prev_dhr: None,
+        cks: Some(hex::encode(cks)),
+        ckr: None,
+        ns: 0,
+        nr: 0,
+        pn: 0,
+        skipped: Vec::new(),
+        init: Some(init),
+        last_used: Utc::now(),
+    })
+}
+
+impl RatchetState {
+    fn encrypt(&mut self, plaintext: &[u8]) -> Result<(RatchetHeader, Vec<u8>), CryptoError> {
+        let cks = unhex("chain key", self.cks.as_deref().ok_or(CryptoError::NoSession)?)?;
+        let (cks, mk) = kdf_ck(&cks)?;
+        let header = RatchetHeader {
+            dh: self.dhs_pub.clone(),
+            pn: self.pn,
+            n: self.ns,
+        };
+        self.cks = Some(hex::encode(cks));
+        self.ns += 1;
+        let ciphertext = self.seal(&mk, &header, plaintext)?;
+        Ok((header, ciphertext))
+    }
+
+    fn decrypt(&mut self, header: &RatchetHeader, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if let Some(pos) = self.skipped.iter().position(|k| k.dh == header.dh && k.n == header.n) {
+            let mk = unhex("message key", &self.skipped[pos].mk)?;
+            let plaintext = self.open(&mk, header, ciphertext)?;
+            self.skipped.remove(pos);
+            return Ok(plaintext);
+        }
+        // no key left for it, so it was read before, e.g. a copy written again by the sender
+        let current = self.dhr.as_deref() == Some(header.dh.as_str());
+        if (current && header.n < self.nr) || self.prev_dhr.as_deref() == Some(header.dh.as_str()) {
+            return Err(CryptoError::AlreadyRead);
+        }
+        if !current {
+            self.skip_until(header.pn)?;
+            self.dh_ratchet(&header.dh)?;
+        }
+        self.skip_until(header.n)?;
+        let ckr = unhex("chain key", self.ckr.as_deref().ok_or(CryptoError::NoSession)?)?;
+        let (ckr, mk) = kdf_ck(&ckr)?;
+        self.ckr = Some(hex::encode(ckr));
+        self.nr += 1;
+        self.open(&mk, header, ciphertext)
+    }
+
+    fn skip_until(&mut self, until: u32) -> Result<(), CryptoError> {
+        let (ckr, dhr) = match (&self.ckr, &self.dhr) {
+            (Some(ckr), Some(dhr)) => (ckr.clone(), dhr.clone()),
+            _ => return Ok(()),
+        };
+        if until > self.nr + MAX_SKIP {
+            return Err(CryptoError::PaddingOrAuth);
+        }
+        let mut ck = unhex("chain key", &ckr)?;
+        while self.nr < until {
+            let (next, mk) = kdf_ck(&ck)?;
+            self.skipped.push(SkippedKey {
+                dh: dhr.clone(),
+                n: self.nr,
+                mk: hex::encode(mk),
+            });
+            ck = next;
+            self.nr += 1;
+        }
+        self.ckr = Some(hex::encode(ck));
+        if self.skipped.len() > MAX_SKIPPED_KEYS {
+            let excess = self.skipped.len() - MAX_SKIPPED_KEYS;
+            self.skipped.drain(..excess);
+        }
+        Ok(())
+    }
+
+    fn dh_ratchet(&mut self, their: &str) -> Result<(), CryptoError> {
+        let their_key = public_from_hex(their)?;
+        let dhs = private_from_pem(&self.dhs)?;
+        let (root_key, ckr) = kdf_rk(&unhex("root key", &self.root_key)?, &x25519(&dhs, &their_key)?)?;
+        let next = PKey::generate_x25519().map_err(CryptoError::Internal)?;
+        let (root_key, cks) = kdf_rk(&root_key, &x25519(&next, &their_key)?)?;
+        self.pn = self.ns;
+        self.ns = 0;
+        self.nr = 0;
+        self.prev_dhr = self.dhr.take();
+        self.dhr = Some(their.to_owned());
+        self.root_key = hex::encode(root_key);
+        self.ckr = Some(hex::encode(ckr));
+        self.cks = Some(hex::encode(cks));
+        self.dhs = private_pem(&next)?;
+        self.dhs_pub = public_hex(&next)?;
+        Ok(())
+    }
+
+    fn aad(&self, header: &RatchetHeader) -> Result<Vec<u8>, CryptoError> {
+        let mut aad = self.ad.as_bytes().to_vec();
+        aad.extend(self.id.as_bytes());
+        aad.extend(serde_json::to_vec(header).map_err(|_| CryptoError::Decode("header".to_owned()))?);
+        Ok(aad)
+    }
+
+    fn seal(&self, mk: &[u8], header: &RatchetHeader, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let (key, nonce) = message_keys(mk)?;
+        seal_aead(&key, &nonce, &self.aad(header)?, plaintext)
+    }
+
+    fn open(&self, mk: &[u8], header: &RatchetHeader, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let (key, nonce) = message_keys(mk)?;
+        open_aead(&key, &nonce, &self.aad(header)?, ciphertext)
+    }
+}
+
+fn curve_dh_keys<'a>(
+    key: &'a InMemoryKey,
+    peer: &'a CachedUser,
+) -> Result<(&'a PKey<Private>, &'a PKey<Public>), CryptoError> {
+    match (key.private(), &peer.identity) {
+        (PrivateIdentity::Curve25519 { dh, .. }, PublicIdentity::Curve25519 { dh: their, .. }) => Ok((dh, their)),
+        _ => Err(CryptoError::NoSession),
+    }
+}
+
+/// Root key KDF, returns the next root key and a chain key.
+fn kdf_rk(root_key: &[u8], dh_out: &[u8]) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+    let mut out = hkdf_sha256(root_key, dh_out, b"yap ratchet", 2 * AEAD_KEY_LEN)?;
+    let chain_key = out.split_off(AEAD_KEY_LEN);
+    Ok((out, chain_key))
+}
+
+/// Chain key KDF, also used for group sender keys. Returns the next chain key and a message key.
+pub fn kdf_ck(chain_key: &[u8]) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+    Ok((hmac_sha256(chain_key, &[2])?, hmac_sha256(chain_key, &[1])?))
+}
+
+pub fn message_keys(mk: &[u8]) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+    let mut out = hkdf_sha256(&[0; 32], mk, b"yap message keys", AEAD_KEY_LEN + AEAD_NONCE_LEN)?;
+    let nonce = out.split_off(AEAD_KEY_LEN);
+    Ok((out, nonce))
+}
+
+fn prekey_bytes(key: &str, created: &DateTime<Utc>) -> Vec<u8> {
+    format!("yap signed prekey\n{}\n{}", key, created.to_rfc3339()).into_bytes()
+}
+
+fn prekey_id(public: &str) -> Result<String, CryptoError> {
+    let der = unhex("prekey", public)?;
+    Ok(hex::encode(hash(MessageDigest::sha256(), &der).map_err(CryptoError::Internal)?))
+}
+
+fn session_id(ephemeral: &str, prekey: &str) -> Result<String, CryptoError> {
+    let mut data = unhex("ephemeral key", ephemeral)?;
+    data.extend(unhex("prekey", prekey)?);
+    let digest = hash(MessageDigest::sha256(), &data).map_err(CryptoError::Internal)?;
+    Ok(hex::encode(&digest[..16]))
+}
+
+fn public_hex<T: HasPublic>(pkey: &PKey<T>) -> Result<String, CryptoError> {
+    Ok(hex::encode(pkey.public_key_to_der().map_err(CryptoError::Internal)?))
+}
+
+fn public_from_hex(public: &str) -> Result<PKey<Public>, CryptoError> {
+    public_from_der(&unhex("public key", public)?, Id::X25519)
+}
+
+fn private_pem(pkey: &PKey<Private>) -> Result<String, CryptoError> {
+    String::from_utf8(pkey.private_key_to_pem_pkcs8().map_err(CryptoError::Internal)?).map_err(CryptoError::Utf8)
+}
+
+fn private_from_pem(pem: &str) -> Result<PKey<Private>, CryptoError> {
+    PKey::private_key_from_pem(pem.as_bytes()).map_err(CryptoError::KeyParse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Peer {
+        key: InMemoryKey,
+        user: CachedUser,
+        sessions: Sessions,
+    }
+
+    fn peer(uid: u32) -> Peer {
+        let identity = LocalIdentity::generate(KeyAlgorithm::Curve25519, "passphrase").unwrap();
+        let key = InMemoryKey::unlock(&identity, &[], "passphrase").unwrap();
+        let user = CachedUser::new(PublicUserRecord {
+            uid: UserId::from(uid),
+            email: None,
+            pubkey: Pubkey::from(identity.pubkey.clone()),
+            hashed_pass: None,
+            alias: None,
+            friends: None,
+            groups: None,
+            motd: None,
+            online: true,
+            key_change: None,
+            devices: vec![],
+        })
+        .unwrap();
+        // never saved, so the file is never created
+        let path = std::env::temp_dir().join(format!("yap-test-{}-{}.sessions.json", std::process::id(), uid));
+        let sessions = Sessions::load(path, &key).unwrap();
+        Peer { key, user, sessions }
+    }
+
+    fn send(from: &mut Peer, to: &Peer, prekey: Option<&SignedPrekey>, text: &str) -> ClientMessage {
+        from.sessions.encrypt(&from.key, &to.user, prekey, text).unwrap()
+    }
+
+    fn receive(to: &mut Peer, from: &Peer, msg: ClientMessage) -> Result<String, CryptoError> {
+        to.sessions.decrypt(&to.key, &from.user, msg)
+    }
+
+    #[test]
+    fn round_trip_with_out_of_order_delivery() {
+        let (mut alice, mut bob) = (peer(1), peer(2));
+        let prekey = bob.sessions.prekey_to_publish(&bob.key).unwrap().unwrap();
+        bob.sessions.prekey_published();
+        assert!(bob.sessions.prekey_to_publish(&bob.key).unwrap().is_none());
+        assert!(alice.sessions.needs_prekey(&bob.user));
+
+        let a1 = send(&mut alice, &bob, Some(&prekey), "a1");
+        let a2 = send(&mut alice, &bob, None, "a2");
+        let a3 = send(&mut alice, &bob, None, "a3");
+        assert_eq!(receive(&mut bob, &alice, a3.clone()).unwrap(), "a3");
+        assert!(matches!(receive(&mut bob, &alice, a3), Err(CryptoError::AlreadyRead)));
+        assert_eq!(receive(&mut bob, &alice, a1.clone()).unwrap(), "a1");
+
+        // the reply moves both sides to a new chain, a2 is still readable from the skipped keys
+        let b1 = send(&mut bob, &alice, None, "b1");
+        assert_eq!(receive(&mut alice, &bob, b1).unwrap(), "b1");
+        let a4 = send(&mut alice, &bob, None, "a4");
+        assert_eq!(receive(&mut bob, &alice, a4).unwrap(), "a4");
+        assert_eq!(receive(&mut bob, &alice, a2.clone()).unwrap(), "a2");
+        // copies from the previous chain are recognised too
+        assert!(matches!(receive(&mut bob, &alice, a2), Err(CryptoError::AlreadyRead)));
+        assert!(matches!(receive(&mut bob, &alice, a1), Err(CryptoError::AlreadyRead)));
+
+        for i in 0..3 {
+            let text = format!("b{}", i + 2);
+            let msg = send(&mut bob, &alice, None, &text);
+            assert_eq!(receive(&mut alice, &bob, msg).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn tampered_message_leaves_session_intact() {
+        let (mut alice, mut bob) = (peer(1), peer(2));
+        let prekey = bob.sessions.prekey_to_publish(&bob.key).unwrap().unwrap();
+        bob.sessions.prekey_published();
+        let first = send(&mut alice, &bob, Some(&prekey), "first");
+        assert_eq!(receive(&mut bob, &alice, first).unwrap(), "first");
+
+        let forged = send(&mut alice, &bob, None, "forged")
+            .to_string()
+            .replace("\"n\":", "\"n\":1");
+        assert!(receive(&mut bob, &alice, ClientMessage::from(forged)).is_err());
+        let next = send(&mut alice, &bob, None, "next");
+        assert_eq!(receive(&mut bob, &alice, next).unwrap(), "next");
+    }
+}
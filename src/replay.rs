@@ -0,0 +1,342 @@
+use crate::imports::*;
+use crate::symbols::*;
+
+/// Tolerated difference between the time a sender sealed a message and `time_posted` by the server.
+const MAX_CLOCK_SKEW_MINUTES: i64 = 10;
+/// Counters remembered per sender. Anything older than all of them is refused as a replay.
+const REPLAY_WINDOW: usize = 1024;
+
+/// What actually gets encrypted: the text along with who sent it to whom, when, and a counter.
+/// Since it's inside the ciphertext, the server can't replay a message elsewhere or again unnoticed.
+#[derive(Serialize, Deserialize)]
+pub struct MessagePayload {
+    pub from: UserId,
+    pub to: MessageActor,
+    /// Grows with every message sent from an account on a server.
+    pub counter: u64,
+    pub time: DateTime<Utc>,
+    /// Missing from payloads sealed before control messages were marked, those are all text.
+    #[serde(default)]
+    pub kind: PayloadKind,
+    pub body: String,
+}
+
+/// What the body of a `MessagePayload` is. Sealed along with it, so only the sender decides.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PayloadKind {
+    #[default]
+    Text,
+    /// A `DmControl`, handled without being shown.
+    Control,
+}
+
+/// An opened message that passed the replay checks.
+pub enum Delivery {
+    Fresh(PayloadKind, String),
+    /// Sealed much earlier or later than the server claims it was posted.
+    Skewed(PayloadKind, String, chrono::Duration),
+    /// From an older client that doesn't seal metadata, so no guarantees. Always shown as text.
+    Unsealed(String),
+}
+
+impl Delivery {
+    pub fn body(&self) -> &str {
+        match self {
+            Delivery::Fresh(_, body) | Delivery::Skewed(_, body, _) | Delivery::Unsealed(body) => body,
+        }
+    }
+
+    pub fn kind(&self) -> PayloadKind {
+        match self {
+            Delivery::Fresh(kind, _) | Delivery::Skewed(kind, ..) => *kind,
+            Delivery::Unsealed(_) => PayloadKind::Text,
+        }
+    }
+
+    /// Shown next to the sender when something about the message is off.
+    pub fn warning(&self) -> Option<String> {
+        match self {
+            Delivery::Fresh(..) => None,
+            Delivery::Skewed(_, _, skew) => Some(format!("sealed {} min away from posting time", skew.num_minutes())),
+            Delivery::Unsealed(_) => Some("no replay protection".to_owned()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    /// This counter was already seen from the sender. Usually a copy the sender wrote again
+    /// because the server's ack was late, so it isn't reported.
+    Duplicate(u64),
+    /// Older than anything still remembered from the sender.
+    Stale(u64),
+    /// Sealed metadata doesn't match the envelope the server delivered it in.
+    SenderMismatch { sealed: UserId, envelope: UserId },
+    RecipientMismatch { sealed: MessageActor, envelope: MessageActor },
+}
+
+impl Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplayError::Duplicate(c) => write!(f, "message {} was already received, replayed by the server?", c),
+            ReplayError::Stale(c) => write!(f, "message {} is too old to check, replayed by the server?", c),
+            ReplayError::SenderMismatch { sealed, envelope } => {
+                write!(f, "sealed by {} but delivered as from {}", sealed, envelope)
+            }
+            ReplayError::RecipientMismatch { sealed, envelope } => {
+                write!(f, "sealed for {} but delivered to {}", sealed, envelope)
+            }
+        }
+    }
+}
+
+impl Error for ReplayError {}
+
+/// Counters of sent and received messages, per server.
+#[derive(Serialize, Deserialize, Default)]
+pub struct ReplayGuard {
+    #[serde(skip)]
+    path: PathBuf,
+    /// Keyed by the server's http address.
+    servers: HashMap<String, ServerCounters>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct ServerCounters {
+    /// Next counter to send with.
+    next: u64,
+    /// Recently seen counters per sender, ascending.
+    seen: HashMap<UserId, Vec<u64>>,
+}
+
+impl ReplayGuard {
+    /// Kept next to the config, e.g. `alice.json` -> `alice.replay.json`.
+    pub fn path_for(cfg_path: &Path) -> PathBuf {
+        cfg_path.with_extension("replay.json")
+    }
+
+    /// A missing file just means nothing has been sent or received yet.
+    pub fn load(path: PathBuf) -> Result<Self, ConfigError> {
+        let mut guard = if path.exists() {
+            load_private_json::<ReplayGuard>(&path)?
+        } else {
+            ReplayGuard::default()
+        };
+        guard.path = path;
+        Ok(guard)
+    }
+
+    pub fn save(&self) -> Result<(), ConfigError> {
+        save_private_json(&self.path, self)
+    }
+
+    /// Wraps `body` into a `MessagePayload`, ready to be encrypted.
+    pub fn seal(&mut self, server: &str, from: UserId, to: MessageActor, kind: PayloadKind, body: &str) -> String {
+        let counters = self.servers.entry(server.to_owned()).or_default();
+        let now = Utc::now();
+        // never below the clock, so losing this file doesn't make contacts refuse everything we send
+        let counter = counters.next.max(now.timestamp_millis() as u64);
+        counters.next = counter + 1;
+        serde_json::to_string(&MessagePayload {
+            from,
+            to,
+            counter,
+            time: now,
+            kind,
+            body: body.to_owned(),
+        })
+        .unwrap()
+    }
+
+    /// Checks a decrypted message against the envelope it came in and what was seen from the sender.
+    /// The counter is remembered, so calling this again with the same message fails.
+    pub fn open(
+        &mut self,
+        server: &str,
+        from: UserId,
+        to: MessageActor,
+        time_posted: DateTime<Utc>,
+        plaintext: &str,
+    ) -> Result<Delivery, ReplayError> {
+        let payload = match unseal(from, to, plaintext)? {
+            Some(p) => p,
+            None => return Ok(Delivery::Unsealed(plaintext.to_owned())),
+        };
+        let seen = self
+            .servers
+            .entry(server.to_owned())
+            .or_default()
+            .seen
+            .entry(from)
+            .or_default();
+        match seen.binary_search(&payload.counter) {
+            Ok(_) => return Err(ReplayError::Duplicate(payload.counter)),
+            Err(0) if seen.len() >= REPLAY_WINDOW => return Err(ReplayError::Stale(payload.counter)),
+            Err(pos) => seen.insert(pos, payload.counter),
+        }
+        if seen.len() > REPLAY_WINDOW {
+            seen.remove(0);
+        }
+        Ok(checked_skew(payload, time_posted))
+    }
+
+    /// Like `open`, for messages that may have been shown before, e.g. from `/history`.
+    /// Only the envelope is checked, counters are neither compared nor remembered.
+    pub fn reopen(
+        from: UserId,
+        to: MessageActor,
+        time_posted: DateTime<Utc>,
+        plaintext: &str,
+    ) -> Result<Delivery, ReplayError> {
+        match unseal(from, to, plaintext)? {
+            Some(payload) => Ok(checked_skew(payload, time_posted)),
+            None => Ok(Delivery::Unsealed(plaintext.to_owned())),
+        }
+    }
+}
+
+/// The sealed payload if it matches the envelope, `None` if the message isn't sealed at all.
+fn unseal(from: UserId, to: MessageActor, plaintext: &str) -> Result<Option<MessagePayload>, ReplayError> {
+    let payload = match serde_json::from_str::<MessagePayload>(plaintext) {
+        Ok(p) => p,
+        Err(_) => return Ok(None),
+    };
+    if payload.from != from {
+        return Err(ReplayError::SenderMismatch {
+            sealed: payload.from,
+            envelope: from,
+        });
+    }
+    if payload.to != to {
+        return Err(ReplayError::RecipientMismatch {
+            sealed: payload.to,
+            envelope: to,
+        });
+    }
+    Ok(Some(payload))
+}
+
+fn checked_skew(payload: MessagePayload, time_posted: DateTime<Utc>) -> Delivery {
+    let skew = payload.time - time_posted;
+    if skew.num_minutes().abs() > MAX_CLOCK_SKEW_MINUTES {
+        Delivery::Skewed(payload.kind, payload.body, skew)
+    } else {
+        Delivery::Fresh(payload.kind, payload.body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: &str = "http://localhost:8080";
+
+    fn sealed(from: UserId, to: MessageActor, counter: u64) -> String {
+        serde_json::to_string(&MessagePayload {
+            from,
+            to,
+            counter,
+            time: Utc::now(),
+            kind: PayloadKind::Text,
+            body: format!("message {}", counter),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn duplicates_are_refused() {
+        let (alice, bob) = (UserId::from(1), UserId::from(2));
+        let mut sender = ReplayGuard::default();
+        let mut guard = ReplayGuard::default();
+        let first = sender.seal(SERVER, alice, MessageActor::Dm(bob), PayloadKind::Text, "hi");
+        let second = sender.seal(SERVER, alice, MessageActor::Dm(bob), PayloadKind::Text, "there");
+        let open =
+            |guard: &mut ReplayGuard, msg: &str| guard.open(SERVER, alice, MessageActor::Dm(bob), Utc::now(), msg);
+        assert!(matches!(open(&mut guard, &second), Ok(Delivery::Fresh(_, ref body)) if body == "there"));
+        assert!(matches!(open(&mut guard, &second), Err(ReplayError::Duplicate(_))));
+        // arriving out of order is fine, as long as it's the first time
+        assert!(matches!(open(&mut guard, &first), Ok(Delivery::Fresh(_, ref body)) if body == "hi"));
+        assert!(matches!(open(&mut guard, &first), Err(ReplayError::Duplicate(_))));
+        // reopen neither checks nor remembers counters
+        assert!(ReplayGuard::reopen(alice, MessageActor::Dm(bob), Utc::now(), &first).is_ok());
+    }
+
+    #[test]
+    fn kind_comes_from_the_sender() {
+        let (alice, bob) = (UserId::from(1), UserId::from(2));
+        let mut sender = ReplayGuard::default();
+        let mut guard = ReplayGuard::default();
+        let looks_like_control = r#"{"SenderKeyRequest":1}"#;
+        let text = sender.seal(SERVER, alice, MessageActor::Dm(bob), PayloadKind::Text, looks_like_control);
+        let control = sender.seal(SERVER, alice, MessageActor::Dm(bob), PayloadKind::Control, looks_like_control);
+        let open = |guard: &mut ReplayGuard, msg: &str| {
+            guard.open(SERVER, alice, MessageActor::Dm(bob), Utc::now(), msg).unwrap().kind()
+        };
+        assert_eq!(open(&mut guard, &text), PayloadKind::Text);
+        assert_eq!(open(&mut guard, &control), PayloadKind::Control);
+        // payloads sealed before the kind existed are text
+        let old = sealed(alice, MessageActor::Dm(bob), 1).replace(r#""kind":"Text","#, "");
+        assert_eq!(open(&mut guard, &old), PayloadKind::Text);
+    }
+
+    #[test]
+    fn counters_older_than_the_window_are_stale() {
+        let (alice, bob) = (UserId::from(1), UserId::from(2));
+        let mut guard = ReplayGuard::default();
+        let mut open = |counter| {
+            guard.open(
+                SERVER,
+                alice,
+                MessageActor::Dm(bob),
+                Utc::now(),
+                &sealed(alice, MessageActor::Dm(bob), counter),
+            )
+        };
+        for counter in 1..=REPLAY_WINDOW as u64 {
+            assert!(open(counter).is_ok());
+        }
+        assert!(matches!(open(0), Err(ReplayError::Stale(0))));
+        // the oldest counter is forgotten once a newer one comes in, and is stale from then on
+        assert!(open(REPLAY_WINDOW as u64 + 1).is_ok());
+        assert!(matches!(open(1), Err(ReplayError::Stale(1))));
+        assert!(matches!(open(2), Err(ReplayError::Duplicate(2))));
+    }
+
+    #[test]
+    fn envelope_must_match_sealed_metadata() {
+        let (alice, bob, eve) = (UserId::from(1), UserId::from(2), UserId::from(3));
+        let mut guard = ReplayGuard::default();
+        let msg = sealed(alice, MessageActor::Dm(bob), 1);
+        assert!(matches!(
+            guard.open(SERVER, eve, MessageActor::Dm(bob), Utc::now(), &msg),
+            Err(ReplayError::SenderMismatch { .. })
+        ));
+        assert!(matches!(
+            guard.open(SERVER, alice, MessageActor::Group(GroupId::from(1)), Utc::now(), &msg),
+            Err(ReplayError::RecipientMismatch { .. })
+        ));
+        assert!(matches!(
+            ReplayGuard::reopen(alice, MessageActor::Dm(eve), Utc::now(), &msg),
+            Err(ReplayError::RecipientMismatch { .. })
+        ));
+        // mismatches aren't remembered, the real delivery still goes through
+        assert!(matches!(
+            guard.open(SERVER, alice, MessageActor::Dm(bob), Utc::now(), &msg),
+            Ok(Delivery::Fresh(..))
+        ));
+    }
+
+    #[test]
+    fn skewed_and_unsealed_messages_carry_a_warning() {
+        let (alice, bob) = (UserId::from(1), UserId::from(2));
+        let mut guard = ReplayGuard::default();
+        let msg = sealed(alice, MessageActor::Dm(bob), 1);
+        let posted = Utc::now() - chrono::Duration::hours(1);
+        let delivery = guard.open(SERVER, alice, MessageActor::Dm(bob), posted, &msg).unwrap();
+        assert!(matches!(delivery, Delivery::Skewed(..)) && delivery.warning().is_some());
+        let delivery = guard
+            .open(SERVER, alice, MessageActor::Dm(bob), Utc::now(), "plain")
+            .unwrap();
+        assert!(matches!(delivery, Delivery::Unsealed(ref body) if body == "plain") && delivery.warning().is_some());
+    }
+}
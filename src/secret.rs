@@ -0,0 +1,37 @@
+use crate::imports::*;
+use zeroize::Zeroize;
+
+/// Passwords, password hashes and other strings that must not end up in logs.
+///
+/// `Debug` is redacted and there is no `Display`, so the value only leaves through `expose`.
+/// Zeroed when dropped. Serializes as the plain string, e.g. for requests to the server.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(s: String) -> Self {
+        SecretString(s)
+    }
+}
+
+impl std::fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SecretString(<redacted>)")
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// Raw key material, zeroed when dropped.
+pub type SecretBytes = zeroize::Zeroizing<Vec<u8>>;